@@ -0,0 +1,60 @@
+// The shader of `BlockMaterial` (see src/block_material.rs).
+
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+#import bevy_pbr::mesh_functions
+#import bevy_core_pipeline::tonemapping
+
+struct BlockMaterial {
+    sky_darkening: f32,
+};
+
+@group(1) @binding(0)
+var<uniform> material: BlockMaterial;
+@group(1) @binding(1)
+var atlas_texture: texture_2d<f32>;
+@group(1) @binding(2)
+var atlas_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    // The shade and tint of the face.
+    @location(2) color: vec4<f32>,
+    // The sky light and block light levels in front of the face.
+    @location(3) light: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) light: vec2<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
+    out.uv = vertex.uv;
+    out.color = vertex.color;
+    out.light = vertex.light;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = textureSample(atlas_texture, atlas_sampler, in.uv);
+    if texel.a < 0.5 {
+        discard;
+    }
+    // The brighter of the dimmed sky light and block light,
+    // from about 0.04 of full brightness in the dark to 1 in full light.
+    let level = max(in.light.x - material.sky_darkening, in.light.y);
+    let brightness = pow(0.8, 15.0 - level);
+    var color = vec4<f32>(texel.rgb * in.color.rgb * brightness, 1.0);
+#ifdef TONEMAP_IN_SHADER
+    color = tone_mapping(color);
+#endif
+    return color;
+}
@@ -0,0 +1,94 @@
+use crate::*;
+use bevy::prelude::*;
+use bevy::tasks::{IoTaskPool, Task};
+use std::path::Path;
+
+/// Plugin responsible for saving the world periodically while in game,
+/// keeping a few backups of earlier saves.
+pub struct AutosavePlugin;
+impl Plugin for AutosavePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AutosaveSettings>();
+        app.add_system(autosave.in_set(OnUpdate(GameState::InGame)));
+    }
+}
+
+/**
+How often the world is autosaved, and how many backups are kept for each world. This is a global resource.
+They can be given on the command line with `--autosave-interval <seconds>` and `--max-backups <number>`.
+*/
+#[derive(Resource)]
+pub struct AutosaveSettings {
+    /// Seconds between autosaves.
+    pub interval: f32,
+    /// The oldest backups are deleted when a world has more than this many.
+    pub max_backups: usize,
+}
+
+impl Default for AutosaveSettings {
+    fn default() -> Self {
+        AutosaveSettings {
+            interval: 300.,
+            max_backups: 3,
+        }
+    }
+}
+
+/// Back up the previous save of a world, and rotate out old backups. Failures are reported.
+fn back_up(name: &str, max_backups: usize) {
+    let saves_dir = Path::new(region::SAVES_DIR);
+    match region::create_backup(saves_dir, name) {
+        Ok(_) => {
+            if let Err(err) = region::rotate_backups(saves_dir, name, max_backups) {
+                println!("Failed to delete old backups of {}: {}", name, err);
+            }
+        }
+        Err(err) => println!("Failed to back up {}: {}", name, err),
+    }
+}
+
+/**
+Save the world every `AutosaveSettings::interval` seconds of playing.
+The previous save is backed up first in a background task, so that copying the world folder does not freeze the game;
+the world is saved in the first frame after the backup is done.
+A failed autosave is reported, and retried at the next interval.
+*/
+fn autosave(
+    time: Res<Time>,
+    settings: Res<AutosaveSettings>,
+    mut game_map: ResMut<gamemap::GameMap>,
+    world_name: Res<gamemap::WorldName>,
+    block_registry: Res<blocks::BlockRegistry>,
+    mut elapsed: Local<f32>,
+    // The world being backed up, and the task backing it up.
+    mut backup_task: Local<Option<(String, Task<()>)>>,
+) {
+    if let Some((_name, task)) = backup_task.as_ref() {
+        if !task.is_finished() {
+            return;
+        }
+        let (name, _task) = backup_task.take().unwrap();
+        // The player may have left for another world meanwhile.
+        if world_name.name.as_ref() == Some(&name) {
+            match gamemap::save_gamemap(&mut game_map, &world_name, &block_registry) {
+                Ok(_) => println!("Autosaved world to {}", name),
+                Err(err) => println!("Autosave failed: {}", err),
+            }
+        }
+        return;
+    }
+
+    *elapsed += time.delta_seconds();
+    if *elapsed < settings.interval {
+        return;
+    }
+    *elapsed = 0.;
+
+    let name = world_name.name.clone().unwrap();
+    let max_backups = settings.max_backups;
+    let task = IoTaskPool::get().spawn({
+        let name = name.clone();
+        async move { back_up(&name, max_backups) }
+    });
+    *backup_task = Some((name, task));
+}
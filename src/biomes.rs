@@ -0,0 +1,192 @@
+//! Biomes
+//! ---
+//! Every column of the world belongs to a biome, chosen by two smooth noises: temperature and humidity.
+//! A biome controls the shape of the terrain (its base height and how hilly it is)
+//! and the blocks on the surface.
+//!
+//! Terrain shape is blended between nearby biomes, so there are no cliffs on biome borders.
+//! Surface blocks are not blended.
+
+use crate::*;
+use noise::{NoiseFn, Perlin};
+use serde::{Deserialize, Serialize};
+
+/// Climate noise is sampled at this scale (in blocks), so biomes are a few hundred blocks wide.
+const CLIMATE_SCALE: f64 = 256.0;
+/// Terrain shape is averaged over biomes within this distance (in blocks).
+const BLEND_RADIUS: i32 = 8;
+/// Distance (in blocks) between samples when blending.
+const BLEND_STEP: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Biome {
+    Plains,
+    Desert,
+    Forest,
+    Mountains,
+    Snowy,
+}
+
+/// How a biome shapes the terrain and what its surface is made of.
+pub struct BiomeProperties {
+    pub name: &'static str,
+    /// The average terrain height.
+    pub base_height: f64,
+    /// How far the terrain height varies from `base_height`.
+    pub height_variation: f64,
+    /// The top block of each column.
+    pub surface_block: &'static str,
+    /// The blocks under the surface block, above stone.
+    pub subsurface_block: &'static str,
+    /// Number of subsurface blocks.
+    pub subsurface_depth: usize,
+    /// Rain falls as snow, and snow stays on the ground, at and above this height.
+    pub snow_line: usize,
+}
+
+impl Biome {
+    pub fn properties(&self) -> BiomeProperties {
+        return match self {
+            Biome::Plains => BiomeProperties {
+                name: "plains",
+                base_height: 14.,
+                height_variation: 6.,
+                surface_block: "grass_block",
+                subsurface_block: "dirt",
+                subsurface_depth: 3,
+                snow_line: usize::MAX,
+            },
+            Biome::Desert => BiomeProperties {
+                name: "desert",
+                base_height: 13.,
+                height_variation: 4.,
+                surface_block: "sand",
+                subsurface_block: "sandstone",
+                subsurface_depth: 3,
+                snow_line: usize::MAX,
+            },
+            Biome::Forest => BiomeProperties {
+                name: "forest",
+                base_height: 15.,
+                height_variation: 8.,
+                surface_block: "grass_block",
+                subsurface_block: "dirt",
+                subsurface_depth: 4,
+                snow_line: usize::MAX,
+            },
+            Biome::Mountains => BiomeProperties {
+                name: "mountains",
+                base_height: 18.,
+                height_variation: 16.,
+                surface_block: "grass_block",
+                subsurface_block: "dirt",
+                subsurface_depth: 1,
+                snow_line: 30,
+            },
+            Biome::Snowy => BiomeProperties {
+                name: "snowy",
+                base_height: 15.,
+                height_variation: 10.,
+                surface_block: "snowy_grass_block",
+                subsurface_block: "dirt",
+                subsurface_depth: 3,
+                snow_line: 0,
+            },
+        };
+    }
+
+    /// The biome with the name in its properties, if any.
+    pub fn from_name(name: &str) -> Option<Biome> {
+        let biomes = [
+            Biome::Plains,
+            Biome::Desert,
+            Biome::Forest,
+            Biome::Mountains,
+            Biome::Snowy,
+        ];
+        return biomes
+            .into_iter()
+            .find(|biome| biome.properties().name == name);
+    }
+
+    /// Choose the biome of a climate. Both temperature and humidity are roughly in -1..1.
+    pub fn from_climate(temperature: f64, humidity: f64) -> Biome {
+        if temperature < -0.4 {
+            return Biome::Snowy;
+        }
+        if temperature < -0.15 {
+            return Biome::Mountains;
+        }
+        if temperature > 0.3 && humidity < 0.1 {
+            return Biome::Desert;
+        }
+        if humidity > 0.2 {
+            return Biome::Forest;
+        }
+        return Biome::Plains;
+    }
+}
+
+/**
+Finds the biome and the terrain shape of any column in a world. It is created from the world seed,
+or with a single biome for the whole world.
+*/
+pub struct BiomeSampler {
+    temperature: Perlin,
+    humidity: Perlin,
+    /// If set, every column has this biome, and climate noise is not used.
+    single_biome: Option<Biome>,
+}
+
+impl BiomeSampler {
+    pub fn new(seed: u64) -> BiomeSampler {
+        return BiomeSampler {
+            temperature: Perlin::new(gamemap::derive_seed(seed, 3) as u32),
+            humidity: Perlin::new(gamemap::derive_seed(seed, 4) as u32),
+            single_biome: None,
+        };
+    }
+
+    /// A sampler where every column has the same biome.
+    pub fn single(biome: Biome) -> BiomeSampler {
+        return BiomeSampler {
+            temperature: Perlin::new(0),
+            humidity: Perlin::new(0),
+            single_biome: Some(biome),
+        };
+    }
+
+    /// The biome of the column at world coordinates (x, z).
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        if let Some(biome) = self.single_biome {
+            return biome;
+        }
+        let point = [x as f64 / CLIMATE_SCALE, z as f64 / CLIMATE_SCALE];
+        return Biome::from_climate(self.temperature.get(point), self.humidity.get(point));
+    }
+
+    /**
+    The base height and height variation at world coordinates (x, z),
+    averaged over nearby biomes (closer biomes weigh more), so that they change smoothly across borders.
+    */
+    pub fn terrain_shape_at(&self, x: i32, z: i32) -> (f64, f64) {
+        let mut total_weight = 0.;
+        let mut base_height = 0.;
+        let mut height_variation = 0.;
+        for dx in (-BLEND_RADIUS..=BLEND_RADIUS).step_by(BLEND_STEP as usize) {
+            for dz in (-BLEND_RADIUS..=BLEND_RADIUS).step_by(BLEND_STEP as usize) {
+                let distance = ((dx * dx + dz * dz) as f64).sqrt();
+                let weight = (BLEND_RADIUS as f64 + 1. - distance).max(0.);
+                if weight == 0. {
+                    continue;
+                }
+                let properties = self.biome_at(x + dx, z + dz).properties();
+                total_weight += weight;
+                base_height += weight * properties.base_height;
+                height_variation += weight * properties.height_variation;
+            }
+        }
+        return (base_height / total_weight, height_variation / total_weight);
+    }
+}
@@ -0,0 +1,75 @@
+//! Block material
+//! ---
+//! Blocks are drawn with a material of their own, `BlockMaterial` (with the shader `assets/shaders/block.wgsl`),
+//! textured with the block texture atlas and not lit by the lights of the scene.
+//! Each vertex of a block mesh carries the shade and tint of its face as its color, and the sky light and block light
+//! in front of the face as `ATTRIBUTE_LIGHT` (see the `chunk_mesh` module).
+//! The shader dims sky light by `BlockMaterial::sky_darkening`, which follows the time of day and the weather
+//! (see `daycycle::sky_darkening`), so chunk meshes are not rebuilt when the sky darkens or brightens.
+
+use bevy::pbr::{MaterialPipeline, MaterialPipelineKey};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::mesh::{MeshVertexAttribute, MeshVertexBufferLayout};
+use bevy::render::render_resource::{
+    AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, VertexFormat,
+};
+
+/// Plugin responsible for rendering meshes with the block material.
+pub struct BlockMaterialPlugin;
+impl Plugin for BlockMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        // Blocks are unlit, so they need no prepass.
+        app.add_plugin(MaterialPlugin::<BlockMaterial> {
+            prepass_enabled: false,
+            ..default()
+        });
+    }
+}
+
+/// The sky light and block light levels in front of the face of a vertex, as `f32`s.
+pub const ATTRIBUTE_LIGHT: MeshVertexAttribute =
+    MeshVertexAttribute::new("BlockLight", 517_230_114, VertexFormat::Float32x2);
+
+/// The material shared by all blocks. Texels with alpha below 0.5 (e.g. around the leaves of plants) are not drawn.
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "6d0c8f3e-2a8b-4e57-9a43-5b1f2c7e9d61"]
+pub struct BlockMaterial {
+    /// How many levels sky light is dimmed by. Kept up to date by `daycycle::update_sky`.
+    #[uniform(0)]
+    pub sky_darkening: f32,
+    /// The block texture atlas.
+    #[texture(1)]
+    #[sampler(2)]
+    pub texture: Handle<Image>,
+}
+
+impl Material for BlockMaterial {
+    fn vertex_shader() -> ShaderRef {
+        return "shaders/block.wgsl".into();
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        return "shaders/block.wgsl".into();
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        return AlphaMode::Mask(0.5);
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(2),
+            ATTRIBUTE_LIGHT.at_shader_location(3),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        return Ok(());
+    }
+}
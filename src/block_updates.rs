@@ -0,0 +1,289 @@
+//! Block updates
+//! ---
+//! Blocks react to changes around them, and act later or by themselves, through callbacks of their kind of blocks
+//! (see `BlockProperties::on_block_update`, `on_scheduled_tick` and `on_random_tick`):
+//! - When a block is changed with `BlockWorld::set`, it and its six neighbors get a block update.
+//!   Block updates are queued, and done in the next game tick.
+//! - A block can schedule a tick of its position some game ticks later with `BlockWorld::schedule_tick`.
+//!   Scheduled ticks are stored in their chunk and saved with it (see the `region` module).
+//!   The ticks of an unloaded chunk wait until it is loaded again, and are done at once if they are due.
+//! - In each game tick, `GameMap::random_tick_speed` random blocks of each chunk section get a random tick.
+//!   Slow changes like growing plants use them (see the `vegetation` module).
+//!
+//! Callbacks can also spawn entities with `BlockWorld::spawn_entity`, e.g. falling blocks
+//! (see the `falling_blocks` module). They are spawned in the next frame.
+//!
+//! Game ticks are the fixed updates in game (see the `entities` module), counted by `GameMap::tick`.
+//! This is the foundation of blocks that change by themselves, like flowing fluids (see the `fluids` module).
+
+use crate::*;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Plugin responsible for doing block updates and scheduled ticks.
+pub struct BlockUpdatePlugin;
+impl Plugin for BlockUpdatePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BlockUpdates>();
+        app.add_system(
+            run_block_updates
+                .in_schedule(CoreSchedule::FixedUpdate)
+                .run_if(in_state(GameState::InGame)),
+        );
+        app.add_system(spawn_new_entities.in_set(OnUpdate(GameState::InGame)));
+        app.add_system(clear_block_updates.in_schedule(OnEnter(GameState::Loading)));
+    }
+}
+
+/// At most this many block updates are done in each game tick. The rest wait for the next one.
+const MAX_BLOCK_UPDATES_PER_TICK: usize = 4096;
+
+/// The offsets of the six neighbors of a block.
+pub const NEIGHBORS: [(i32, i32, i32); 6] = [
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 1, 0),
+    (0, -1, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+];
+
+pub fn add((x, y, z): (i32, i32, i32), (dx, dy, dz): (i32, i32, i32)) -> (i32, i32, i32) {
+    return (x + dx, y + dy, z + dz);
+}
+
+/// A callback of a kind of blocks, called with the position of the block.
+pub type BlockCallback = fn(&mut BlockWorld, (i32, i32, i32));
+
+/// A tick scheduled at a block of a chunk, in local coordinates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledTick {
+    pub x: u8,
+    pub y: u16,
+    pub z: u8,
+    /// The game tick it is due (see `GameMap::tick`).
+    pub due: u64,
+}
+
+/**
+Positions waiting for a block update, in the order their blocks changed,
+and entities spawned by block callbacks, waiting to be spawned. This is a global resource.
+*/
+#[derive(Resource, Default)]
+pub struct BlockUpdates {
+    queue: VecDeque<(i32, i32, i32)>,
+    new_entities: Vec<entities::EntityStatus>,
+}
+
+/**
+Access to the blocks of loaded chunks for block callbacks, and for anything else that changes blocks
+and should let blocks react: light, chunk meshes and block updates are kept up to date by `set`.
+*/
+pub struct BlockWorld<'a> {
+    pub game_map: &'a gamemap::GameMap,
+    pub block_registry: &'a blocks::BlockRegistry,
+    pub light_map: &'a mut lighting::LightMap,
+    pub chunk_meshes: &'a mut chunk_mesh::ChunkMeshes,
+    pub block_updates: &'a mut BlockUpdates,
+}
+
+impl<'a> BlockWorld<'a> {
+    /// The block at integer world coordinates. None below y = 0, above the world, or in a chunk that is not loaded.
+    pub fn block(&self, position: (i32, i32, i32)) -> Option<i32> {
+        return self.game_map.block_at(position);
+    }
+
+    /// The light level at integer world coordinates: the brighter of sky light and block light.
+    pub fn light(&self, position: (i32, i32, i32)) -> u8 {
+        let (sky_light, block_light) = self.light_map.light_at(position);
+        return sky_light.max(block_light);
+    }
+
+    /**
+    Set the block at integer world coordinates, update light and chunk meshes,
+    and queue block updates of it and its neighbors.
+    Returns whether the block was set (false below y = 0, above the world, or in a chunk that is not loaded).
+    */
+    pub fn set(&mut self, position: (i32, i32, i32), block_id: i32) -> bool {
+        if !self.game_map.set_block_at(position, block_id) {
+            return false;
+        }
+        self.chunk_meshes.mark_block_dirty(position);
+        for changed_chunk in
+            self.light_map
+                .update_block(self.game_map, position, self.block_registry)
+        {
+            self.chunk_meshes.mark_chunk_dirty(changed_chunk);
+        }
+        self.block_updates.queue.push_back(position);
+        for offset in NEIGHBORS {
+            self.block_updates.queue.push_back(add(position, offset));
+        }
+        return true;
+    }
+
+    /**
+    Schedule a tick of the block at the position, `delay` game ticks from now.
+    Does nothing if a tick is already scheduled there, or the chunk is not loaded.
+    */
+    pub fn schedule_tick(&mut self, (x, y, z): (i32, i32, i32), delay: u64) {
+        if y < 0 || y >= self.game_map.height as i32 {
+            return;
+        }
+        let size = gamemap::CHUNK_SIZE as i32;
+        let chunk = match self
+            .game_map
+            .map
+            .get(&(x.div_euclid(size), z.div_euclid(size)))
+        {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        let (x, y, z) = (x.rem_euclid(size) as u8, y as u16, z.rem_euclid(size) as u8);
+        let mut scheduled_ticks = chunk.scheduled_ticks.lock().unwrap();
+        if scheduled_ticks
+            .iter()
+            .any(|tick| (tick.x, tick.y, tick.z) == (x, y, z))
+        {
+            return;
+        }
+        scheduled_ticks.push(ScheduledTick {
+            x,
+            y,
+            z,
+            due: self.game_map.tick + delay,
+        });
+    }
+
+    /// Spawn an entity in the chunk it is in. Nothing is spawned if the chunk is unloaded meanwhile.
+    pub fn spawn_entity(&mut self, entity_status: entities::EntityStatus) {
+        self.block_updates.new_entities.push(entity_status);
+    }
+
+    /// Call a callback of the block at the position, if its kind of blocks has one.
+    fn call(
+        &mut self,
+        position: (i32, i32, i32),
+        callback: impl Fn(&blocks::BlockProperties) -> Option<BlockCallback>,
+    ) {
+        let callback = match self
+            .block(position)
+            .and_then(|block_id| self.block_registry.get(block_id))
+            .and_then(callback)
+        {
+            Some(callback) => callback,
+            None => return,
+        };
+        callback(self, position);
+    }
+}
+
+/// Forget the block updates of the previous world. Scheduled ticks are in its chunks, and saved with them.
+fn clear_block_updates(mut block_updates: ResMut<BlockUpdates>) {
+    block_updates.queue.clear();
+    block_updates.new_entities.clear();
+}
+
+/// Spawn the entities spawned by block callbacks, and add them to their chunks.
+fn spawn_new_entities(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut game_map: ResMut<gamemap::GameMap>,
+    mut block_updates: ResMut<BlockUpdates>,
+    game_assets: Res<init_game::GameAssets>,
+) {
+    for entity_status in block_updates.new_entities.drain(..) {
+        let chunk_key = game_map.query_chunk_by_xyz(entity_status.position);
+        let chunk = match game_map.map.get_mut(&chunk_key) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        let entity_status = Arc::new(Mutex::new(entity_status));
+        init_game::spawn_entity(&mut commands, &entity_status, &game_assets, &mut meshes);
+        chunk.entities.push(entity_status);
+    }
+}
+
+/// `GameMap::random_tick_speed` random positions in each section of loaded chunks, for random ticks.
+fn random_tick_positions(
+    game_map: &gamemap::GameMap,
+) -> impl Iterator<Item = (i32, i32, i32)> + '_ {
+    let size = gamemap::CHUNK_SIZE as i32;
+    let section_height = sections::SECTION_HEIGHT as i32;
+    let mut rng = rand::thread_rng();
+    return game_map
+        .map
+        .keys()
+        .flat_map(move |&chunk_key| {
+            (0..game_map.height as i32)
+                .step_by(sections::SECTION_HEIGHT)
+                .flat_map(move |section_y| {
+                    (0..game_map.random_tick_speed).map(move |_| (chunk_key, section_y))
+                })
+        })
+        .map(move |(chunk_key, section_y)| {
+            (
+                chunk_key.0 * size + rng.gen_range(0, size),
+                section_y + rng.gen_range(0, section_height),
+                chunk_key.1 * size + rng.gen_range(0, size),
+            )
+        });
+}
+
+/// Count the game tick, then do the scheduled ticks that are due, random ticks and the queued block updates.
+fn run_block_updates(
+    mut game_map: ResMut<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
+    mut light_map: ResMut<lighting::LightMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    mut block_updates: ResMut<BlockUpdates>,
+) {
+    game_map.tick += 1;
+    let now = game_map.tick;
+    let size = gamemap::CHUNK_SIZE as i32;
+    let mut due = Vec::new();
+    for (&chunk_key, chunk) in game_map.map.iter() {
+        let mut scheduled_ticks = chunk.scheduled_ticks.lock().unwrap();
+        scheduled_ticks.retain(|tick| {
+            if tick.due > now {
+                return true;
+            }
+            due.push((
+                tick.due,
+                (
+                    chunk_key.0 * size + tick.x as i32,
+                    tick.y as i32,
+                    chunk_key.1 * size + tick.z as i32,
+                ),
+            ));
+            return false;
+        });
+    }
+    // Overdue ticks (of chunks loaded again) first.
+    due.sort_by_key(|(due, _position)| *due);
+
+    let mut world = BlockWorld {
+        game_map: &game_map,
+        block_registry: &block_registry,
+        light_map: &mut light_map,
+        chunk_meshes: &mut chunk_meshes,
+        block_updates: &mut block_updates,
+    };
+    for (_due, position) in due {
+        world.call(position, |block| block.on_scheduled_tick);
+    }
+    let game_map = world.game_map;
+    for position in random_tick_positions(game_map) {
+        world.call(position, |block| block.on_random_tick);
+    }
+    for _ in 0..MAX_BLOCK_UPDATES_PER_TICK {
+        let position = match world.block_updates.queue.pop_front() {
+            Some(position) => position,
+            None => break,
+        };
+        world.call(position, |block| block.on_block_update);
+    }
+}
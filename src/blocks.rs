@@ -1,5 +1,364 @@
+use crate::*;
 use bevy::prelude::*;
+use std::collections::HashMap;
 
-/// A "tag" component for all blocks.
-#[derive(Component)]
-pub struct Block;
+/// The numeric block ID of air. Any negative block ID is treated as air.
+pub const AIR: i32 = -1;
+
+/// Texture names of a block's faces.
+/// Each name is a file name (without `.png`) in `./assets/blocks/`.
+#[derive(Clone, Debug)]
+pub struct BlockTextures {
+    pub top: String,
+    pub side: String,
+    pub bottom: String,
+}
+
+/// Properties of a kind of blocks.
+#[derive(Clone, Debug)]
+pub struct BlockProperties {
+    /// The stable string ID, e.g. `"stone"`. It never changes between versions.
+    pub name: String,
+    /// Whether entities collide with this block.
+    pub solid: bool,
+    /// Whether blocks behind this block can be seen through it. Light also goes through transparent blocks.
+    pub transparent: bool,
+    /// The light level this block gives off (see the `lighting` module). 0 for most blocks.
+    pub light_emission: u8,
+    /// The fluid this block is made of, with its level: 0 for a source, or the distance it has flowed.
+    /// None for blocks other than fluids. See the `fluids` module.
+    pub fluid: Option<(fluids::Fluid, u8)>,
+    /// The color the textures are multiplied by. White for most blocks.
+    pub tint: Color,
+    /// Whether this block falls when there is nothing under it, like sand. See the `falling_blocks` module.
+    pub falls: bool,
+    /// Called when this block or a block next to it changes. See the `block_updates` module.
+    pub on_block_update: Option<block_updates::BlockCallback>,
+    /// Called when a tick scheduled at this block is due. See the `block_updates` module.
+    pub on_scheduled_tick: Option<block_updates::BlockCallback>,
+    /// Called when this block is picked for a random tick. See the `block_updates` module.
+    pub on_random_tick: Option<block_updates::BlockCallback>,
+    /// Textures of the faces.
+    pub textures: BlockTextures,
+}
+
+impl BlockProperties {
+    /// A solid, opaque block with the same texture on all faces.
+    pub fn cube(name: &str) -> Self {
+        BlockProperties {
+            name: name.to_string(),
+            solid: true,
+            transparent: false,
+            light_emission: 0,
+            fluid: None,
+            tint: Color::WHITE,
+            falls: false,
+            on_block_update: None,
+            on_scheduled_tick: None,
+            on_random_tick: None,
+            textures: BlockTextures {
+                top: name.to_string(),
+                side: name.to_string(),
+                bottom: name.to_string(),
+            },
+        }
+    }
+    /// A solid, opaque block with different top, side and bottom textures.
+    pub fn top_side_bottom(name: &str, top: &str, side: &str, bottom: &str) -> Self {
+        BlockProperties {
+            name: name.to_string(),
+            solid: true,
+            transparent: false,
+            light_emission: 0,
+            fluid: None,
+            tint: Color::WHITE,
+            falls: false,
+            on_block_update: None,
+            on_scheduled_tick: None,
+            on_random_tick: None,
+            textures: BlockTextures {
+                top: top.to_string(),
+                side: side.to_string(),
+                bottom: bottom.to_string(),
+            },
+        }
+    }
+    /// A solid, opaque block like logs, with `<name>_top` on top and bottom.
+    pub fn column(name: &str) -> Self {
+        let end = format!("{}_top", name);
+        BlockProperties::top_side_bottom(name, &end, name, &end)
+    }
+    /// Mark this block as transparent.
+    pub fn transparent(mut self) -> Self {
+        self.transparent = true;
+        return self;
+    }
+    /// Mark this block as non-solid (entities go through it). Non-solid blocks are also transparent.
+    pub fn non_solid(mut self) -> Self {
+        self.solid = false;
+        self.transparent = true;
+        return self;
+    }
+    /// Make this block give off light of the level.
+    pub fn emits_light(mut self, level: u8) -> Self {
+        self.light_emission = level;
+        return self;
+    }
+    /// Make this block a fluid of the level (0 for a source). Fluids are non-solid,
+    /// and opaque unless marked `transparent` too.
+    pub fn fluid(mut self, fluid: fluids::Fluid, level: u8) -> Self {
+        self.fluid = Some((fluid, level));
+        self.solid = false;
+        return self;
+    }
+    /// Multiply the textures of this block by the color, e.g. for gray water textures.
+    pub fn tinted(mut self, tint: Color) -> Self {
+        self.tint = tint;
+        return self;
+    }
+    /// Make this block fall when there is nothing under it.
+    pub fn falls(mut self) -> Self {
+        self.falls = true;
+        self.on_block_update = Some(falling_blocks::on_block_update);
+        self.on_scheduled_tick = Some(falling_blocks::on_scheduled_tick);
+        return self;
+    }
+    /// Call the callback when this block or a block next to it changes.
+    pub fn on_block_update(mut self, callback: block_updates::BlockCallback) -> Self {
+        self.on_block_update = Some(callback);
+        return self;
+    }
+    /// Call the callback when a tick scheduled at this block is due.
+    pub fn on_scheduled_tick(mut self, callback: block_updates::BlockCallback) -> Self {
+        self.on_scheduled_tick = Some(callback);
+        return self;
+    }
+    /// Call the callback when this block is picked for a random tick.
+    pub fn on_random_tick(mut self, callback: block_updates::BlockCallback) -> Self {
+        self.on_random_tick = Some(callback);
+        return self;
+    }
+}
+
+/**
+The registry of all kinds of blocks. This is a global resource.
+
+It maps stable string IDs (e.g. `"stone"`, `"grass_block"`) to the numeric IDs stored in chunks.
+Numeric IDs are given in the order of registration,
+so new blocks must be registered after all existing ones.
+
+Usage:
+```
+let stone: i32 = block_registry.id("stone").unwrap();
+let solid: bool = block_registry.is_solid(stone);
+```
+ */
+#[derive(Resource)]
+pub struct BlockRegistry {
+    blocks: Vec<BlockProperties>,
+    ids: HashMap<String, i32>,
+}
+
+impl BlockRegistry {
+    /// Returns an empty registry.
+    pub fn empty() -> Self {
+        BlockRegistry {
+            blocks: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+    /// Register a kind of blocks and returns its numeric ID.
+    /// Panics if the name is already registered.
+    pub fn register(&mut self, properties: BlockProperties) -> i32 {
+        let id = self.blocks.len() as i32;
+        if self.ids.insert(properties.name.clone(), id).is_some() {
+            panic!("Block registered twice: {}", properties.name);
+        }
+        self.blocks.push(properties);
+        return id;
+    }
+    /// Query the numeric ID of a block by its string ID.
+    pub fn id(&self, name: &str) -> Option<i32> {
+        return self.ids.get(name).copied();
+    }
+    /// Query the properties of a block by its numeric ID.
+    /// Returns None for air and unknown IDs.
+    pub fn get(&self, id: i32) -> Option<&BlockProperties> {
+        if id < 0 {
+            return None;
+        }
+        return self.blocks.get(id as usize);
+    }
+    /// Whether the block is solid. Air and unknown blocks are not solid.
+    pub fn is_solid(&self, id: i32) -> bool {
+        return self.get(id).map_or(false, |block| block.solid);
+    }
+    /// Whether the block is transparent. Air and unknown blocks are transparent.
+    pub fn is_transparent(&self, id: i32) -> bool {
+        return self.get(id).map_or(true, |block| block.transparent);
+    }
+    /// The light level the block gives off. Air and unknown blocks give off no light.
+    pub fn light_emission(&self, id: i32) -> u8 {
+        return self.get(id).map_or(0, |block| block.light_emission);
+    }
+    /// The fluid the block is made of and its level, if it is a fluid.
+    pub fn fluid(&self, id: i32) -> Option<(fluids::Fluid, u8)> {
+        return self.get(id).and_then(|block| block.fluid);
+    }
+    /// Returns the string IDs of all blocks, indexed by numeric ID.
+    /// This is the block palette stored in save files.
+    pub fn palette(&self) -> Vec<String> {
+        return self.blocks.iter().map(|block| block.name.clone()).collect();
+    }
+    /// Iterate through all blocks with their numeric IDs.
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &BlockProperties)> {
+        return self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| (id as i32, block));
+    }
+}
+
+impl Default for BlockRegistry {
+    /// Returns a registry with all built-in blocks.
+    fn default() -> Self {
+        let mut registry = BlockRegistry::empty();
+        // NOTE: Only append to this list. Inserting in the middle changes numeric IDs.
+        registry.register(BlockProperties::cube("stone"));
+        registry.register(
+            BlockProperties::top_side_bottom(
+                "grass_block",
+                "grass_block_top",
+                "grass_block_side",
+                "dirt",
+            )
+            .on_random_tick(vegetation::grow_grass),
+        );
+        registry.register(BlockProperties::cube("dirt"));
+        registry.register(BlockProperties::cube("cobblestone"));
+        registry.register(BlockProperties::cube("bedrock"));
+        registry.register(BlockProperties::cube("sand").falls());
+        registry.register(BlockProperties::cube("gravel").falls());
+        registry.register(BlockProperties::cube("oak_planks"));
+        registry.register(BlockProperties::column("oak_log"));
+        registry.register(
+            BlockProperties::cube("oak_leaves")
+                .transparent()
+                .on_random_tick(vegetation::decay_leaves),
+        );
+        registry.register(BlockProperties::column("birch_log"));
+        registry.register(
+            BlockProperties::cube("birch_leaves")
+                .transparent()
+                .on_random_tick(vegetation::decay_leaves),
+        );
+        registry.register(BlockProperties::column("spruce_log"));
+        registry.register(
+            BlockProperties::cube("spruce_leaves")
+                .transparent()
+                .on_random_tick(vegetation::decay_leaves),
+        );
+        registry.register(BlockProperties::top_side_bottom(
+            "barrel",
+            "barrel_top",
+            "barrel_side",
+            "barrel_bottom",
+        ));
+        registry.register(BlockProperties::cube("glass").transparent());
+        registry.register(BlockProperties::cube("bricks"));
+        registry.register(BlockProperties::top_side_bottom(
+            "sandstone",
+            "sandstone_top",
+            "sandstone",
+            "sandstone_bottom",
+        ));
+        registry.register(BlockProperties::top_side_bottom(
+            "snow_block",
+            "snow",
+            "snow",
+            "snow",
+        ));
+        registry.register(BlockProperties::top_side_bottom(
+            "snowy_grass_block",
+            "snow",
+            "grass_block_snow",
+            "dirt",
+        ));
+        registry.register(BlockProperties::cube("coal_ore"));
+        registry.register(BlockProperties::cube("copper_ore"));
+        registry.register(BlockProperties::cube("iron_ore"));
+        registry.register(BlockProperties::cube("gold_ore"));
+        registry.register(BlockProperties::cube("redstone_ore"));
+        registry.register(BlockProperties::cube("lapis_ore"));
+        registry.register(BlockProperties::cube("diamond_ore"));
+        registry.register(BlockProperties::top_side_bottom(
+            "cactus",
+            "cactus_top",
+            "cactus_side",
+            "cactus_bottom",
+        ));
+        registry.register(BlockProperties::cube("grass").non_solid());
+        registry.register(BlockProperties::cube("dandelion").non_solid());
+        registry.register(BlockProperties::cube("poppy").non_solid());
+        registry.register(BlockProperties::cube("allium").non_solid());
+        registry.register(BlockProperties::cube("azure_bluet").non_solid());
+        registry.register(BlockProperties::cube("dead_bush").non_solid());
+        registry.register(BlockProperties::cube("glowstone").emits_light(15));
+        registry.register(
+            BlockProperties::top_side_bottom("lava", "lava_still", "lava_still", "lava_still")
+                .fluid(fluids::Fluid::Lava, 0)
+                .transparent()
+                .emits_light(15)
+                .on_block_update(fluids::on_block_update)
+                .on_scheduled_tick(fluids::on_scheduled_tick),
+        );
+        registry.register(
+            BlockProperties::top_side_bottom("water", "water_still", "water_still", "water_still")
+                .fluid(fluids::Fluid::Water, 0)
+                .transparent()
+                .tinted(fluids::WATER_TINT)
+                .on_block_update(fluids::on_block_update)
+                .on_scheduled_tick(fluids::on_scheduled_tick),
+        );
+        for level in 1..=fluids::Fluid::Water.max_level() {
+            registry.register(
+                BlockProperties::top_side_bottom(
+                    &fluids::Fluid::Water.block_name(level),
+                    "water_still",
+                    "water_flow",
+                    "water_still",
+                )
+                .fluid(fluids::Fluid::Water, level)
+                .transparent()
+                .tinted(fluids::WATER_TINT)
+                .on_block_update(fluids::on_block_update)
+                .on_scheduled_tick(fluids::on_scheduled_tick),
+            );
+        }
+        for level in 1..=fluids::Fluid::Lava.max_level() {
+            registry.register(
+                BlockProperties::top_side_bottom(
+                    &fluids::Fluid::Lava.block_name(level),
+                    "lava_still",
+                    "lava_flow",
+                    "lava_still",
+                )
+                .fluid(fluids::Fluid::Lava, level)
+                .transparent()
+                .emits_light(15)
+                .on_block_update(fluids::on_block_update)
+                .on_scheduled_tick(fluids::on_scheduled_tick),
+            );
+        }
+        registry.register(BlockProperties::cube("obsidian"));
+        for sapling in ["oak_sapling", "birch_sapling", "spruce_sapling"] {
+            registry.register(
+                BlockProperties::cube(sapling)
+                    .non_solid()
+                    .on_random_tick(vegetation::grow_sapling),
+            );
+        }
+        return registry;
+    }
+}
@@ -0,0 +1,159 @@
+use crate::*;
+use bevy::prelude::*;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Plugin responsible for loading chunks near the main player, and unloading chunks far away.
+pub struct ChunkLoadingPlugin;
+impl Plugin for ChunkLoadingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderDistance>();
+        app.add_systems(
+            (update_entity_chunks, unload_far_chunks, load_chunks_near_player)
+                .chain()
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+    }
+}
+
+/// Chunks within this distance (in chunks) from the main player are loaded. This is a global resource.
+#[derive(Resource)]
+pub struct RenderDistance {
+    pub chunks: i32,
+}
+
+impl Default for RenderDistance {
+    fn default() -> Self {
+        RenderDistance { chunks: 4 }
+    }
+}
+
+/// Chunks are unloaded only when they are this much farther than the render distance,
+/// so that walking back and forth across a chunk border does not reload chunks again and again.
+const UNLOAD_MARGIN: i32 = 1;
+/// At most this many chunks are loaded each frame, to avoid stuttering.
+const CHUNKS_LOADED_PER_FRAME: usize = 4;
+
+/// Squared distance (in chunks) between two chunks.
+fn chunk_distance_squared(chunk1: (i32, i32), chunk2: (i32, i32)) -> i32 {
+    let dx = chunk1.0 - chunk2.0;
+    let dz = chunk1.1 - chunk2.1;
+    return dx * dx + dz * dz;
+}
+
+/// Keep every entity in the chunk that contains it, so that it is unloaded together with that chunk.
+fn update_entity_chunks(mut game_map: ResMut<gamemap::GameMap>) {
+    game_map.update_entity_chunks();
+}
+
+/**
+Load (or generate) and spawn the nearest missing chunks within render distance of the main player.
+Chunks that fail to load are reported once, and are not loaded again.
+*/
+fn load_chunks_near_player(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut game_map: ResMut<gamemap::GameMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    game_assets: Res<init_game::GameAssets>,
+    block_registry: Res<blocks::BlockRegistry>,
+    world_name: Res<gamemap::WorldName>,
+    render_distance: Res<RenderDistance>,
+    query_main_player: Query<&Transform, With<player::MainPlayer>>,
+    mut failed_chunks: Local<HashSet<(i32, i32)>>,
+) {
+    let transform = query_main_player
+        .get_single()
+        .expect("Not exactly one main player!");
+    let player_chunk = game_map.query_chunk_by_xyz(transform.translation);
+    let distance = render_distance.chunks;
+    let mut missing_chunks = vec![];
+    for chunk_x in player_chunk.0 - distance..=player_chunk.0 + distance {
+        for chunk_z in player_chunk.1 - distance..=player_chunk.1 + distance {
+            let chunk_key = (chunk_x, chunk_z);
+            if chunk_distance_squared(chunk_key, player_chunk) <= distance * distance
+                && !game_map.map.contains_key(&chunk_key)
+                && !failed_chunks.contains(&chunk_key)
+            {
+                missing_chunks.push(chunk_key);
+            }
+        }
+    }
+    missing_chunks.sort_by_key(|&chunk_key| chunk_distance_squared(chunk_key, player_chunk));
+
+    let world_dir = region::world_dir(
+        Path::new(region::SAVES_DIR),
+        world_name.name.as_ref().unwrap(),
+    );
+    for chunk_key in missing_chunks.into_iter().take(CHUNKS_LOADED_PER_FRAME) {
+        match game_map.load_chunk(chunk_key, &world_dir, &block_registry) {
+            Ok(()) => init_game::spawn_chunk(
+                &mut commands,
+                chunk_key,
+                &game_map.map[&chunk_key],
+                &game_assets,
+                &mut chunk_meshes,
+                &mut meshes,
+            ),
+            Err(err) => {
+                println!("Failed to load chunk {:?}: {}", chunk_key, err);
+                failed_chunks.insert(chunk_key);
+            }
+        }
+    }
+}
+
+/**
+Save and despawn chunks out of render distance of the main player.
+A chunk that fails to be saved stays loaded.
+*/
+fn unload_far_chunks(
+    mut commands: Commands,
+    mut game_map: ResMut<gamemap::GameMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    world_name: Res<gamemap::WorldName>,
+    render_distance: Res<RenderDistance>,
+    query_main_player: Query<&Transform, With<player::MainPlayer>>,
+    query_entities: Query<(Entity, &entities::EntityStatusPointer)>,
+) {
+    let transform = query_main_player
+        .get_single()
+        .expect("Not exactly one main player!");
+    let player_chunk = game_map.query_chunk_by_xyz(transform.translation);
+    let unload_distance = render_distance.chunks + UNLOAD_MARGIN;
+    let far_chunks: Vec<(i32, i32)> = game_map
+        .map
+        .keys()
+        .copied()
+        .filter(|&chunk_key| {
+            chunk_distance_squared(chunk_key, player_chunk) > unload_distance * unload_distance
+        })
+        .collect();
+
+    let world_dir = region::world_dir(
+        Path::new(region::SAVES_DIR),
+        world_name.name.as_ref().unwrap(),
+    );
+    for chunk_key in far_chunks {
+        let chunk = match game_map.unload_chunk(chunk_key, &world_dir) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                println!("Failed to save chunk {:?}: {}", chunk_key, err);
+                continue;
+            }
+        };
+        // Despawn the chunk's mesh, and show the faces of its neighbors that it hid.
+        chunk_meshes.mark_chunk_and_neighbors_dirty(chunk_key);
+        // Despawn all entities in the chunk.
+        for (entity, status_ptr) in query_entities.iter() {
+            if chunk
+                .entities
+                .iter()
+                .any(|chunk_entity| Arc::ptr_eq(chunk_entity, &status_ptr.pointer))
+            {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use crate::*;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use std::collections::{HashMap, HashSet};
+
+/// Plugin responsible for building meshes of chunks.
+/// All blocks in a chunk are rendered by one mesh with the block texture atlas,
+/// with faces hidden by other blocks culled.
+/// Faces are shaded by the light in front of them (see the `lighting` module) and by their direction.
+pub struct ChunkMeshPlugin;
+impl Plugin for ChunkMeshPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChunkMeshes>();
+        app.add_system(rebuild_dirty_chunk_meshes.in_set(OnUpdate(GameState::InGame)));
+    }
+}
+
+/// A component for the mesh entity of a chunk.
+#[derive(Component)]
+pub struct ChunkMesh;
+
+/**
+The mesh entities of all loaded chunks, and the chunks whose meshes need rebuilding.
+This is a global resource.
+
+Mark a chunk dirty whenever its blocks change, is loaded or is unloaded.
+Its mesh is rebuilt (or despawned, if the chunk is unloaded) in the next update.
+ */
+#[derive(Resource, Default)]
+pub struct ChunkMeshes {
+    pub entities: HashMap<(i32, i32), Entity>,
+    pub dirty: HashSet<(i32, i32)>,
+}
+
+impl ChunkMeshes {
+    /// Mark a chunk's mesh to be rebuilt.
+    pub fn mark_chunk_dirty(&mut self, chunk_key: (i32, i32)) {
+        self.dirty.insert(chunk_key);
+    }
+    /// Mark a chunk and its four neighbors to be rebuilt.
+    /// Used when a chunk is loaded or unloaded, since faces on the neighbors' borders may become hidden or visible.
+    pub fn mark_chunk_and_neighbors_dirty(&mut self, (chunk_x, chunk_z): (i32, i32)) {
+        self.mark_chunk_dirty((chunk_x, chunk_z));
+        self.mark_chunk_dirty((chunk_x - 1, chunk_z));
+        self.mark_chunk_dirty((chunk_x + 1, chunk_z));
+        self.mark_chunk_dirty((chunk_x, chunk_z - 1));
+        self.mark_chunk_dirty((chunk_x, chunk_z + 1));
+    }
+    /// Mark the chunk containing the block to be rebuilt.
+    /// If the block is on the chunk's border, the neighbor chunk is also marked.
+    pub fn mark_block_dirty(&mut self, (x, _y, z): (i32, i32, i32)) {
+        let size = gamemap::CHUNK_SIZE as i32;
+        let chunk_key = (x.div_euclid(size), z.div_euclid(size));
+        self.mark_chunk_dirty(chunk_key);
+        match x.rem_euclid(size) {
+            0 => self.mark_chunk_dirty((chunk_key.0 - 1, chunk_key.1)),
+            local_x if local_x == size - 1 => self.mark_chunk_dirty((chunk_key.0 + 1, chunk_key.1)),
+            _ => {}
+        }
+        match z.rem_euclid(size) {
+            0 => self.mark_chunk_dirty((chunk_key.0, chunk_key.1 - 1)),
+            local_z if local_z == size - 1 => self.mark_chunk_dirty((chunk_key.0, chunk_key.1 + 1)),
+            _ => {}
+        }
+    }
+}
+
+/// Which texture of a block a face uses.
+enum FaceTexture {
+    Top,
+    Side,
+    Bottom,
+}
+
+/// A face of a block: the texture it uses, the offset to the neighbor block it faces,
+/// its normal, how bright it is in full light, and its four corners (counter-clockwise seen from outside)
+/// with texture coordinates.
+struct Face {
+    texture: FaceTexture,
+    neighbor: [i32; 3],
+    normal: [f32; 3],
+    shade: f32,
+    corners: [([f32; 3], [f32; 2]); 4],
+}
+
+const FACES: [Face; 6] = [
+    // Top.
+    Face {
+        texture: FaceTexture::Top,
+        neighbor: [0, 1, 0],
+        normal: [0., 1., 0.],
+        shade: 1.0,
+        corners: [
+            ([0., 1., 0.], [0., 0.]),
+            ([0., 1., 1.], [0., 1.]),
+            ([1., 1., 1.], [1., 1.]),
+            ([1., 1., 0.], [1., 0.]),
+        ],
+    },
+    // Bottom.
+    Face {
+        texture: FaceTexture::Bottom,
+        neighbor: [0, -1, 0],
+        normal: [0., -1., 0.],
+        shade: 0.5,
+        corners: [
+            ([0., 0., 0.], [0., 1.]),
+            ([1., 0., 0.], [1., 1.]),
+            ([1., 0., 1.], [1., 0.]),
+            ([0., 0., 1.], [0., 0.]),
+        ],
+    },
+    // Right (+X).
+    Face {
+        texture: FaceTexture::Side,
+        neighbor: [1, 0, 0],
+        normal: [1., 0., 0.],
+        shade: 0.6,
+        corners: [
+            ([1., 0., 0.], [1., 1.]),
+            ([1., 1., 0.], [1., 0.]),
+            ([1., 1., 1.], [0., 0.]),
+            ([1., 0., 1.], [0., 1.]),
+        ],
+    },
+    // Left (-X).
+    Face {
+        texture: FaceTexture::Side,
+        neighbor: [-1, 0, 0],
+        normal: [-1., 0., 0.],
+        shade: 0.6,
+        corners: [
+            ([0., 0., 0.], [0., 1.]),
+            ([0., 0., 1.], [1., 1.]),
+            ([0., 1., 1.], [1., 0.]),
+            ([0., 1., 0.], [0., 0.]),
+        ],
+    },
+    // Back (+Z).
+    Face {
+        texture: FaceTexture::Side,
+        neighbor: [0, 0, 1],
+        normal: [0., 0., 1.],
+        shade: 0.8,
+        corners: [
+            ([0., 0., 1.], [0., 1.]),
+            ([1., 0., 1.], [1., 1.]),
+            ([1., 1., 1.], [1., 0.]),
+            ([0., 1., 1.], [0., 0.]),
+        ],
+    },
+    // Front (-Z).
+    Face {
+        texture: FaceTexture::Side,
+        neighbor: [0, 0, -1],
+        normal: [0., 0., -1.],
+        shade: 0.8,
+        corners: [
+            ([0., 0., 0.], [1., 1.]),
+            ([0., 1., 0.], [1., 0.]),
+            ([1., 1., 0.], [0., 0.]),
+            ([1., 0., 0.], [0., 1.]),
+        ],
+    },
+];
+
+/// Vertices and indices of a mesh being built.
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    colors: Vec<[f32; 4]>,
+    lights: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    /// Add a face of the block at (x, y, z), using the texture in `texture_rect` of the atlas,
+    /// lit by the sky light and block light in front of it (see the `block_material` module).
+    fn add_face(
+        &mut self,
+        face: &Face,
+        (x, y, z): (f32, f32, f32),
+        texture_rect: Rect,
+        (sky_light, block_light): (u8, u8),
+        tint: Color,
+    ) {
+        let first_index = self.positions.len() as u32;
+        for (corner, uv) in face.corners.iter() {
+            self.positions
+                .push([x + corner[0], y + corner[1], z + corner[2]]);
+            self.normals.push(face.normal);
+            self.uvs.push([
+                texture_rect.min.x + uv[0] * texture_rect.width(),
+                texture_rect.min.y + uv[1] * texture_rect.height(),
+            ]);
+            self.colors.push([
+                face.shade * tint.r(),
+                face.shade * tint.g(),
+                face.shade * tint.b(),
+                1.,
+            ]);
+            self.lights.push([sky_light as f32, block_light as f32]);
+        }
+        for offset in [0, 1, 2, 0, 2, 3] {
+            self.indices.push(first_index + offset);
+        }
+    }
+    fn build(self) -> Mesh {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, self.colors);
+        mesh.insert_attribute(block_material::ATTRIBUTE_LIGHT, self.lights);
+        mesh.set_indices(Some(Indices::U32(self.indices)));
+        return mesh;
+    }
+}
+
+/**
+Build the mesh of a loaded chunk in the chunk's local coordinates, textured with the block texture atlas.
+A face is built only if the neighbor block is air, or transparent and of another kind
+(blocks of the same fluid count as one kind). Faces are tinted by `BlockProperties::tint`.
+Unloaded chunks hide no faces, so the edge of the loaded area is closed; faces hidden by a neighbor chunk
+are dropped when it is loaded.
+*/
+fn build_chunk_mesh(
+    game_map: &gamemap::GameMap,
+    light_map: &lighting::LightMap,
+    chunk_key: (i32, i32),
+    block_registry: &blocks::BlockRegistry,
+    block_texture_rects: &HashMap<String, Rect>,
+) -> Mesh {
+    let size = gamemap::CHUNK_SIZE as i32;
+    let chunk_blocks = game_map.map[&chunk_key].blocks.lock().unwrap();
+    let height = chunk_blocks.height() as i32;
+    // Returns None below the world. Blocks of unloaded chunks are air.
+    let query_neighbor = |x: i32, y: i32, z: i32| -> Option<i32> {
+        if y < 0 {
+            return None; // Nothing can be seen below the world.
+        }
+        if y >= height {
+            return Some(blocks::AIR);
+        }
+        if 0 <= x && x < size && 0 <= z && z < size {
+            return Some(chunk_blocks.get(x as usize, y as usize, z as usize));
+        }
+        return Some(
+            game_map
+                .query_block_by_xyz(Vec3::new(
+                    (chunk_key.0 * size + x) as f32,
+                    y as f32,
+                    (chunk_key.1 * size + z) as f32,
+                ))
+                .unwrap_or(blocks::AIR),
+        );
+    };
+
+    let mut builder = MeshBuilder::default();
+    let section_height = sections::SECTION_HEIGHT as i32;
+    let air_sections: Vec<bool> = chunk_blocks
+        .sections()
+        .iter()
+        .map(|section| section.is_air())
+        .collect();
+    for x in 0..size {
+        for y in 0..height {
+            if air_sections[(y / section_height) as usize] {
+                continue;
+            }
+            for z in 0..size {
+                let block_id = chunk_blocks.get(x as usize, y as usize, z as usize);
+                let block = match block_registry.get(block_id) {
+                    Some(block) => block,
+                    None => continue, // Air or unknown block.
+                };
+                for face in FACES.iter() {
+                    let neighbor = (
+                        x + face.neighbor[0],
+                        y + face.neighbor[1],
+                        z + face.neighbor[2],
+                    );
+                    let neighbor_id = match query_neighbor(neighbor.0, neighbor.1, neighbor.2) {
+                        Some(neighbor_id) => neighbor_id,
+                        None => continue,
+                    };
+                    // Fluids of the same kind look like one body, whatever their levels.
+                    let same_fluid = block.fluid.is_some()
+                        && block.fluid.map(|(fluid, _)| fluid)
+                            == block_registry.fluid(neighbor_id).map(|(fluid, _)| fluid);
+                    if neighbor_id < 0
+                        || (block_registry.is_transparent(neighbor_id)
+                            && neighbor_id != block_id
+                            && !same_fluid)
+                    {
+                        let texture_name = match face.texture {
+                            FaceTexture::Top => &block.textures.top,
+                            FaceTexture::Side => &block.textures.side,
+                            FaceTexture::Bottom => &block.textures.bottom,
+                        };
+                        let light = light_map.light_at((
+                            chunk_key.0 * size + neighbor.0,
+                            neighbor.1,
+                            chunk_key.1 * size + neighbor.2,
+                        ));
+                        builder.add_face(
+                            face,
+                            (x as f32, y as f32, z as f32),
+                            block_texture_rects[texture_name],
+                            light,
+                            block.tint,
+                        );
+                    }
+                }
+            }
+        }
+    }
+    return builder.build();
+}
+
+/**
+Build the mesh of a single block in full light, textured with the block texture atlas,
+for entities that look like a block (see the `falling_blocks` module).
+It is centered horizontally on the origin, with its bottom at the origin.
+*/
+pub fn build_block_mesh(
+    block: &blocks::BlockProperties,
+    block_texture_rects: &HashMap<String, Rect>,
+) -> Mesh {
+    let mut builder = MeshBuilder::default();
+    for face in FACES.iter() {
+        let texture_name = match face.texture {
+            FaceTexture::Top => &block.textures.top,
+            FaceTexture::Side => &block.textures.side,
+            FaceTexture::Bottom => &block.textures.bottom,
+        };
+        builder.add_face(
+            face,
+            (-0.5, 0., -0.5),
+            block_texture_rects[texture_name],
+            (lighting::MAX_LIGHT, lighting::MAX_LIGHT),
+            block.tint,
+        );
+    }
+    return builder.build();
+}
+
+/**
+Rebuild meshes of dirty chunks. Meshes of unloaded chunks are despawned.
+*/
+pub fn rebuild_dirty_chunk_meshes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+    game_map: Res<gamemap::GameMap>,
+    light_map: Res<lighting::LightMap>,
+    game_assets: Res<init_game::GameAssets>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    let dirty_chunks: Vec<(i32, i32)> = chunk_meshes.dirty.drain().collect();
+    for chunk_key in dirty_chunks {
+        if let Some(old_entity) = chunk_meshes.entities.remove(&chunk_key) {
+            commands.entity(old_entity).despawn_recursive();
+        }
+        if !game_map.map.contains_key(&chunk_key) {
+            continue;
+        }
+        let mesh = build_chunk_mesh(
+            &game_map,
+            &light_map,
+            chunk_key,
+            &block_registry,
+            &game_assets.block_texture_rects,
+        );
+        let chunk_entity = commands
+            .spawn((
+                ChunkMesh,
+                MaterialMeshBundle::<block_material::BlockMaterial> {
+                    mesh: meshes.add(mesh),
+                    material: game_assets.block_material.clone(),
+                    transform: Transform::from_xyz(
+                        (chunk_key.0 * gamemap::CHUNK_SIZE as i32) as f32,
+                        0.,
+                        (chunk_key.1 * gamemap::CHUNK_SIZE as i32) as f32,
+                    ),
+                    ..default()
+                },
+            ))
+            .id();
+        chunk_meshes.entities.insert(chunk_key, chunk_entity);
+    }
+}
@@ -22,13 +22,17 @@ impl Plugin for ControlPlugin {
 }
 
 const MAX_VELOCITY: f32 = 4.;
-/// This system is used to make the main player walk.
+/// Vertical speed of the main player swimming up or down in fluids.
+const SWIM_VELOCITY: f32 = 2.;
+/// This system is used to make the main player walk, and swim in fluids.
 fn walk(
     keys: Res<Input<KeyCode>>,
     mut query_main_player_status: Query<
         (&mut entities::EntityStatusPointer, &Transform),
         With<player::MainPlayer>,
     >,
+    gamemap: Res<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
     // Unwrap the Arc into mutable reference.
     let (status_pointer, transform) = &mut query_main_player_status
@@ -52,11 +56,16 @@ fn walk(
     if keys.pressed(KeyCode::D) {
         status.velocity += transform.right();
     }
+    let vertical_velocity =
+        match fluids::fluid_at(&gamemap, transform.translation, &block_registry) {
+            Some(_fluid) => SWIM_VELOCITY,
+            None => MAX_VELOCITY,
+        };
     if keys.pressed(KeyCode::Space) {
-        status.velocity.y = MAX_VELOCITY;
+        status.velocity.y = vertical_velocity;
     }
     if keys.pressed(KeyCode::LShift) {
-        status.velocity.y = -MAX_VELOCITY;
+        status.velocity.y = -vertical_velocity;
     }
     let abs_velocity: f32 =
         f32::sqrt(status.velocity.x * status.velocity.x + status.velocity.z * status.velocity.z);
@@ -68,12 +77,17 @@ fn walk(
     }
 }
 
-// Random walk for players and creepers.
+// Random walk for players and creepers. Falling blocks and items do not walk.
 fn random_move(
     keys: Res<Input<KeyCode>>,
     mut query_player_status: Query<
         (&mut entities::EntityStatusPointer, &mut Transform),
-        (With<entities::Entity>, Without<player::MainPlayer>)
+        (
+            With<entities::Entity>,
+            Without<player::MainPlayer>,
+            Without<entities::FallingBlock>,
+            Without<entities::Item>,
+        )
     >,
 ){
     for (status_pointer, mut transform) in &mut query_player_status {
@@ -0,0 +1,183 @@
+//! Day and night
+//! ---
+//! The world clock counts the world time in seconds. It advances while in game, and is saved with the world.
+//! A day lasts `DAY_LENGTH` seconds: the sun rises at the start of the day, is highest at a quarter of it,
+//! sets at the half, and the moon is up during the other half.
+//!
+//! The sun and the moon are directional lights that light entities, and the sky color changes with them.
+//! Blocks are lit by sky light (see the `lighting` module), which is dimmed at night and by clouds (see `sky_darkening`).
+
+use crate::*;
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+/// Plugin responsible for advancing the world clock, and moving the sun and the moon.
+pub struct DayCyclePlugin;
+impl Plugin for DayCyclePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WorldClock>();
+        app.add_systems(
+            (advance_clock, update_sky)
+                .chain()
+                .before(chunk_mesh::rebuild_dirty_chunk_meshes)
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+    }
+}
+
+/// Length of a day (including the night) in seconds.
+pub const DAY_LENGTH: f64 = 1200.;
+/// New worlds start a little after sunrise.
+pub const NEW_WORLD_TIME: f64 = DAY_LENGTH * 0.05;
+/// Sky light is dimmed by up to this many levels at night, or by clouds.
+const MAX_SKY_DARKENING: f32 = 11.;
+/// Illuminance of the sun at noon, and of the moon at midnight.
+const SUN_ILLUMINANCE: f32 = 20000.;
+const MOON_ILLUMINANCE: f32 = 1500.;
+
+const DAY_SKY_COLOR: Color = Color::rgb(0.47, 0.65, 1.0);
+const SUNSET_SKY_COLOR: Color = Color::rgb(0.9, 0.55, 0.35);
+const NIGHT_SKY_COLOR: Color = Color::rgb(0.01, 0.01, 0.04);
+/// The color of a cloudy sky during the day.
+const CLOUD_COLOR: Color = Color::rgb(0.45, 0.47, 0.5);
+
+/**
+The world time. This is a global resource, set from `GameMap::time` when a world is loaded,
+and copied back into it as it advances, so that it is saved with the world.
+
+Other systems can use it to tell day from night, e.g. `world_clock.is_day()`.
+*/
+#[derive(Resource, Default)]
+pub struct WorldClock {
+    /// Seconds since the world was created.
+    pub time: f64,
+}
+
+impl WorldClock {
+    /// The number of days since the world was created, starting from 0.
+    pub fn day(&self) -> u64 {
+        return (self.time / DAY_LENGTH) as u64;
+    }
+
+    /// The time of the day in 0..1. 0 is sunrise, 0.25 noon, 0.5 sunset and 0.75 midnight.
+    pub fn time_of_day(&self) -> f32 {
+        return (self.time / DAY_LENGTH).fract() as f32;
+    }
+
+    /// The height of the sun in -1..1: its sine of elevation.
+    pub fn sun_height(&self) -> f32 {
+        return (self.time_of_day() * 2. * PI).sin();
+    }
+
+    /// Whether the sun is up.
+    pub fn is_day(&self) -> bool {
+        return self.sun_height() > 0.;
+    }
+
+    /// Whether the sun is down.
+    pub fn is_night(&self) -> bool {
+        return !self.is_day();
+    }
+
+    /// How bright the sky is in 0..1: 1 during the day and 0 at night, changing gradually around sunrise and sunset.
+    pub fn daylight(&self) -> f32 {
+        return (self.sun_height() * 4. + 0.5).clamp(0., 1.);
+    }
+
+    /// How many levels sky light is dimmed by now, without clouds. 0 during the day.
+    pub fn sky_darkening(&self) -> u8 {
+        return ((1. - self.daylight()) * MAX_SKY_DARKENING).round() as u8;
+    }
+}
+
+/// How many levels sky light is dimmed by now, at night and by clouds. Night with clouds is no darker than night.
+pub fn sky_darkening(world_clock: &WorldClock, weather: &weather::Weather) -> u8 {
+    return (world_clock.sky_darkening() + weather.sky_darkening()).min(MAX_SKY_DARKENING as u8);
+}
+
+/// A component for the directional light of the sun.
+#[derive(Component)]
+pub struct Sun;
+
+/// A component for the directional light of the moon.
+#[derive(Component)]
+pub struct Moon;
+
+/// Spawn the sun and the moon. They are moved into place by `update_sky`.
+pub fn spawn_sun_and_moon(commands: &mut Commands) {
+    let light = |illuminance| DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            illuminance,
+            shadows_enabled: false,
+            ..default()
+        },
+        ..default()
+    };
+    commands.spawn((Sun, light(SUN_ILLUMINANCE)));
+    commands.spawn((Moon, light(MOON_ILLUMINANCE)));
+}
+
+/// Advance the world time, and keep it in the game map so that it is saved with the world.
+fn advance_clock(
+    time: Res<Time>,
+    mut world_clock: ResMut<WorldClock>,
+    mut game_map: ResMut<gamemap::GameMap>,
+) {
+    world_clock.time += time.delta_seconds_f64();
+    game_map.time = world_clock.time;
+}
+
+/**
+Move the sun and the moon, change the sky color and the ambient light,
+and dim the sky light of blocks (see `block_material::BlockMaterial`). Clouds dim the sun and gray the sky.
+*/
+pub fn update_sky(
+    world_clock: Res<WorldClock>,
+    weather: Res<weather::Weather>,
+    game_assets: Res<init_game::GameAssets>,
+    mut block_materials: ResMut<Assets<block_material::BlockMaterial>>,
+    mut clear_color: ResMut<ClearColor>,
+    mut ambient_light: ResMut<AmbientLight>,
+    mut query_sun: Query<(&mut Transform, &mut DirectionalLight), (With<Sun>, Without<Moon>)>,
+    mut query_moon: Query<(&mut Transform, &mut DirectionalLight), (With<Moon>, Without<Sun>)>,
+) {
+    // The sun goes around the X axis, rising in the east (+X).
+    let angle = world_clock.time_of_day() * 2. * PI;
+    let sun_direction = Vec3::new(angle.cos(), angle.sin(), 0.);
+    let sun_height = world_clock.sun_height();
+    let sunlight = 1. - weather.cloudiness() * 0.7;
+    for (mut transform, mut light) in query_sun.iter_mut() {
+        *transform = Transform::IDENTITY.looking_at(-sun_direction, Vec3::Z);
+        light.illuminance = SUN_ILLUMINANCE * sunlight * sun_height.max(0.);
+    }
+    for (mut transform, mut light) in query_moon.iter_mut() {
+        *transform = Transform::IDENTITY.looking_at(sun_direction, Vec3::Z);
+        light.illuminance = MOON_ILLUMINANCE * sunlight * (-sun_height).max(0.);
+    }
+
+    let daylight = world_clock.daylight();
+    // The sky is reddest when the sun is at the horizon.
+    let sunset = (1. - sun_height.abs() * 4.).max(0.);
+    let day_color = lerp_color(NIGHT_SKY_COLOR, DAY_SKY_COLOR, daylight);
+    let sky_color = lerp_color(day_color, SUNSET_SKY_COLOR, sunset * 0.6);
+    let cloud_color = lerp_color(NIGHT_SKY_COLOR, CLOUD_COLOR, daylight);
+    clear_color.0 = lerp_color(sky_color, cloud_color, weather.cloudiness());
+    ambient_light.brightness = (0.1 + 0.3 * daylight) * sunlight;
+
+    // Only a changed material is uploaded again.
+    let sky_darkening = sky_darkening(&world_clock, &weather) as f32;
+    let block_material = &game_assets.block_material;
+    if block_materials.get(block_material).map(|material| material.sky_darkening) != Some(sky_darkening) {
+        if let Some(material) = block_materials.get_mut(block_material) {
+            material.sky_darkening = sky_darkening;
+        }
+    }
+}
+
+fn lerp_color(from: Color, to: Color, t: f32) -> Color {
+    return Color::rgb(
+        from.r() + (to.r() - from.r()) * t,
+        from.g() + (to.g() - from.g()) * t,
+        from.b() + (to.b() - from.b()) * t,
+    );
+}
@@ -0,0 +1,252 @@
+//! Decoration
+//! ---
+//! After the terrain of a chunk is generated, trees and plants are placed on its surface according to biome.
+//!
+//! Trees may straddle chunk borders. A tree belongs to the chunk its trunk grows from,
+//! and all random numbers of a chunk's trees come from the world seed and the chunk's coordinates.
+//! When a chunk is decorated, the trees of its neighbor chunks are grown too (finding their ground
+//! with `TerrainGenerator::column`), and the parts of them inside this chunk are kept.
+//! So a tree is never cut off, no matter in which order chunks are generated.
+//!
+//! Plants are one block high, so they never leave their chunk.
+
+use crate::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Salt of the seeds of trees (see `gamemap::chunk_seed`).
+const TREE_SALT: u64 = 200;
+/// Salt of the seeds of plants.
+const PLANT_SALT: u64 = 201;
+/// Number of places where each chunk tries to grow a tree. Whether a tree grows there depends on the biome.
+const TREE_ATTEMPTS_PER_CHUNK: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum TreeSpecies {
+    Oak,
+    Birch,
+    Spruce,
+    Cactus,
+}
+
+/// The chance that a tree grows at each attempt, and its species, in a biome.
+/// `roll` is a random number in 0..1, used to choose among species.
+fn tree_of_biome(biome: biomes::Biome, roll: f32) -> (f32, TreeSpecies) {
+    return match biome {
+        biomes::Biome::Plains => (0.1, TreeSpecies::Oak),
+        biomes::Biome::Forest => match roll < 0.6 {
+            true => (0.9, TreeSpecies::Oak),
+            false => (0.9, TreeSpecies::Birch),
+        },
+        biomes::Biome::Mountains => (0.15, TreeSpecies::Spruce),
+        biomes::Biome::Snowy => (0.3, TreeSpecies::Spruce),
+        biomes::Biome::Desert => (0.1, TreeSpecies::Cactus),
+    };
+}
+
+/// The plants of a biome, with the chance of each one growing on a surface block.
+fn plants_of_biome(biome: biomes::Biome) -> &'static [(&'static str, f32)] {
+    return match biome {
+        biomes::Biome::Plains => &[("grass", 0.2), ("dandelion", 0.02), ("poppy", 0.02)],
+        biomes::Biome::Forest => &[("grass", 0.1), ("allium", 0.02), ("azure_bluet", 0.02)],
+        biomes::Biome::Mountains => &[("grass", 0.05)],
+        biomes::Biome::Snowy => &[],
+        biomes::Biome::Desert => &[("dead_bush", 0.02)],
+    };
+}
+
+/// Where trees are grown: the blocks of a chunk being decorated, or loaded chunks (see the `vegetation` module).
+pub trait TreeWriter {
+    /// Set the block at (x, y, z) in world coordinates. If `only_replace_air`, other blocks are kept.
+    fn set(&mut self, position: (i32, i32, i32), block_id: i32, only_replace_air: bool);
+}
+
+/// Blocks of a chunk being decorated. Positions are in world coordinates;
+/// blocks outside the chunk are silently dropped.
+struct ChunkWriter<'a> {
+    blocks: &'a mut sections::ChunkBlocks,
+    chunk_key: (i32, i32),
+}
+
+impl<'a> TreeWriter for ChunkWriter<'a> {
+    fn set(&mut self, (x, y, z): (i32, i32, i32), block_id: i32, only_replace_air: bool) {
+        let size = gamemap::CHUNK_SIZE as i32;
+        let local_x = x - self.chunk_key.0 * size;
+        let local_z = z - self.chunk_key.1 * size;
+        if local_x < 0 || local_x >= size || local_z < 0 || local_z >= size || y < 0 {
+            return;
+        }
+        let (local_x, y, local_z) = (local_x as usize, y as usize, local_z as usize);
+        if !only_replace_air || self.blocks.get(local_x, y, local_z) == blocks::AIR {
+            self.blocks.set(local_x, y, local_z, block_id);
+        }
+    }
+}
+
+/// The height of the trunk of a tree. `height_roll` is a random number in 0..3.
+pub fn trunk_height(species: TreeSpecies, height_roll: i32) -> i32 {
+    return match species {
+        TreeSpecies::Oak => 4 + height_roll,
+        TreeSpecies::Birch => 5 + height_roll,
+        TreeSpecies::Spruce => 6 + height_roll,
+        TreeSpecies::Cactus => 1 + height_roll,
+    };
+}
+
+/// Grow a tree whose trunk starts at (x, y, z). `height` is the height of the trunk.
+pub fn grow_tree(
+    writer: &mut impl TreeWriter,
+    species: TreeSpecies,
+    (x, y, z): (i32, i32, i32),
+    height: i32,
+    block_registry: &blocks::BlockRegistry,
+) {
+    let (log, leaves) = match species {
+        TreeSpecies::Oak => ("oak_log", "oak_leaves"),
+        TreeSpecies::Birch => ("birch_log", "birch_leaves"),
+        TreeSpecies::Spruce => ("spruce_log", "spruce_leaves"),
+        TreeSpecies::Cactus => ("cactus", "cactus"),
+    };
+    let log = block_registry.id(log).unwrap();
+    let leaves = block_registry.id(leaves).unwrap();
+    let top = y + height - 1;
+    // Leaves only fill air, and logs replace leaves, so overlapping trees look right in any order.
+    match species {
+        TreeSpecies::Oak | TreeSpecies::Birch => {
+            // Two wide layers around the top of the trunk, then two narrow layers above.
+            for leaves_y in top - 2..=top + 1 {
+                let radius: i32 = if leaves_y < top { 2 } else { 1 };
+                for dx in -radius..=radius {
+                    for dz in -radius..=radius {
+                        // Cut the corners to make it round.
+                        if radius > 1 && dx.abs() == radius && dz.abs() == radius {
+                            continue;
+                        }
+                        if leaves_y == top + 1 && dx != 0 && dz != 0 {
+                            continue;
+                        }
+                        writer.set((x + dx, leaves_y, z + dz), leaves, true);
+                    }
+                }
+            }
+        }
+        TreeSpecies::Spruce => {
+            // A cone, alternating between wider and narrower layers.
+            for leaves_y in y + 2..=top + 1 {
+                let radius: i32 = match top + 1 - leaves_y {
+                    0 => 0,
+                    1 => 1,
+                    from_top => 2 - from_top % 2,
+                };
+                for dx in -radius..=radius {
+                    for dz in -radius..=radius {
+                        if radius > 0 && dx.abs() == radius && dz.abs() == radius {
+                            continue;
+                        }
+                        writer.set((x + dx, leaves_y, z + dz), leaves, true);
+                    }
+                }
+            }
+        }
+        TreeSpecies::Cactus => {}
+    }
+    for log_y in y..=top {
+        writer.set((x, log_y, z), log, false);
+    }
+}
+
+/**
+Place trees and plants on a chunk's blocks, including the parts of trees growing from neighbor chunks.
+The blocks must be the chunk's generated terrain.
+*/
+pub fn decorate(
+    blocks: &mut sections::ChunkBlocks,
+    chunk_key: (i32, i32),
+    terrain_generator: &worldgen::TerrainGenerator,
+) {
+    let size = gamemap::CHUNK_SIZE as i32;
+    let block_registry = terrain_generator.block_registry;
+    let grass_block = block_registry.id("grass_block").unwrap();
+    let snowy_grass_block = block_registry.id("snowy_grass_block").unwrap();
+    let sand = block_registry.id("sand").unwrap();
+
+    // Plants, only in this chunk. They are placed first, so that trees can replace them.
+    let mut rng = StdRng::seed_from_u64(gamemap::chunk_seed(
+        terrain_generator.seed,
+        chunk_key,
+        PLANT_SALT,
+    ));
+    let heightmap = gamemap::surface_heightmap(blocks);
+    for x in 0..gamemap::CHUNK_SIZE {
+        for z in 0..gamemap::CHUNK_SIZE {
+            let roll = rng.gen::<f32>();
+            let height = heightmap[x][z];
+            if height == 0 || height >= blocks.height() {
+                continue;
+            }
+            let ground = blocks.get(x, height - 1, z);
+            if ground != grass_block && ground != sand {
+                continue;
+            }
+            let biome = terrain_generator
+                .biome_sampler
+                .biome_at(chunk_key.0 * size + x as i32, chunk_key.1 * size + z as i32);
+            // Choose the plant whose range of `roll` contains it.
+            let mut chance_sum = 0.;
+            for &(plant, chance) in plants_of_biome(biome) {
+                chance_sum += chance;
+                if roll < chance_sum {
+                    blocks.set(x, height, z, block_registry.id(plant).unwrap());
+                    break;
+                }
+            }
+        }
+    }
+
+    // Trees of this chunk and its neighbors, in the same order for every chunk.
+    let mut writer = ChunkWriter { blocks, chunk_key };
+    for dx in -1..=1 {
+        for dz in -1..=1 {
+            let origin_chunk = (chunk_key.0 + dx, chunk_key.1 + dz);
+            let mut rng = StdRng::seed_from_u64(gamemap::chunk_seed(
+                terrain_generator.seed,
+                origin_chunk,
+                TREE_SALT,
+            ));
+            for _ in 0..TREE_ATTEMPTS_PER_CHUNK {
+                // Draw every random number before deciding anything,
+                // so that every attempt uses the same amount of random numbers.
+                let x = origin_chunk.0 * size + rng.gen_range(0, size);
+                let z = origin_chunk.1 * size + rng.gen_range(0, size);
+                let grow_roll = rng.gen::<f32>();
+                let species_roll = rng.gen::<f32>();
+                let height_roll = rng.gen_range(0, 3);
+
+                let (chance, species) =
+                    tree_of_biome(terrain_generator.biome_sampler.biome_at(x, z), species_roll);
+                if grow_roll >= chance {
+                    continue;
+                }
+                let column = terrain_generator.column(x, z);
+                let ground_y = match (0..column.len()).rev().find(|&y| column[y] != blocks::AIR) {
+                    Some(ground_y) => ground_y,
+                    None => continue,
+                };
+                let ground = column[ground_y];
+                let on_proper_ground = match species {
+                    TreeSpecies::Cactus => ground == sand,
+                    _ => ground == grass_block || ground == snowy_grass_block,
+                };
+                if !on_proper_ground {
+                    continue;
+                }
+                grow_tree(
+                    &mut writer,
+                    species,
+                    (x, ground_y as i32 + 1, z),
+                    trunk_height(species, height_roll),
+                    block_registry,
+                );
+            }
+        }
+    }
+}
@@ -3,7 +3,8 @@ use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 
-const TIME_STEP: f32 = 1.0 / 60.0;
+/// The time of a fixed update (a game tick) in seconds.
+pub const TIME_STEP: f32 = 1.0 / 60.0;
 
 /// Plugin responsible for the update of entities.
 /// Currently an entity only move itself duing update stage.
@@ -37,6 +38,14 @@ pub struct HuTao;
 #[derive(Component)]
 pub struct Chicken;
 
+/// A "tag" component for the entity type "FallingBlock", a block falling down (see the `falling_blocks` module).
+#[derive(Component)]
+pub struct FallingBlock;
+
+/// A "tag" component for the entity type "Item", a block dropped as an item.
+#[derive(Component)]
+pub struct Item;
+
 /**
 A ref-counted pointer with lock, pointing to the entity's EntityStatus.
 Use this pointer like this:
@@ -70,6 +79,9 @@ pub struct EntityStatus {
     pub velocity: Vec3,
     /// The attack CD, In seconds.
     pub attack_cd: f32,
+    /// The string ID of the block that a falling block or an item is made of. None for other entities.
+    #[serde(default)]
+    pub block: Option<String>,
 }
 
 fn check_whether_in_game(game_state: Res<State<GameState>>) -> bool {
@@ -83,13 +95,17 @@ fn entity_move(
     mut query_entity_status: Query<(&EntityStatusPointer, &mut Transform), With<Entity>>,
     game_state: Res<State<GameState>>,
     gamemap: Res<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
     if check_whether_in_game(game_state) == false {
         return;
     }
     for (status_ptr, mut transform) in query_entity_status.iter_mut() {
         let mut status: std::sync::MutexGuard<EntityStatus> = status_ptr.pointer.lock().unwrap();
-        let movement = status.velocity * TIME_STEP;
+        // Entities move slower in fluids.
+        let movement_factor = fluids::fluid_at(&gamemap, transform.translation, &block_registry)
+            .map_or(1., |fluid| fluid.movement_factor());
+        let movement = status.velocity * TIME_STEP * movement_factor;
         let mut new_position = transform.translation + movement;
         let mut block_id = gamemap.query_block_by_xyz(new_position);
         let x = transform.translation[0] as i32;
@@ -98,17 +114,17 @@ fn entity_move(
         let newx = new_position[0] as i32;
         let newy = new_position[1] as i32;
         let newz = new_position[2] as i32;
-        if block_id.unwrap_or(-1) >= 0 && y != newy {
+        if block_registry.is_solid(block_id.unwrap_or(blocks::AIR)) && y != newy {
             new_position.y = transform.translation.y;
             status.velocity.y = 0.;
             block_id = gamemap.query_block_by_xyz(new_position);
         }
-        if block_id.unwrap_or(-1) >= 0 && x != newx {
+        if block_registry.is_solid(block_id.unwrap_or(blocks::AIR)) && x != newx {
             new_position.x = transform.translation.x;
             status.velocity.x = 0.;
             block_id = gamemap.query_block_by_xyz(new_position);
         }
-        if block_id.unwrap_or(-1) >= 0 && z != newz {
+        if block_registry.is_solid(block_id.unwrap_or(blocks::AIR)) && z != newz {
             new_position.z = transform.translation.z;
             status.velocity.z = 0.;
         }
@@ -124,14 +140,25 @@ fn gravity(
     mut query_entity_status: Query<&EntityStatusPointer, With<Entity>>,
     gamemap: Res<gamemap::GameMap>,
     game_state: Res<State<GameState>>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
     if check_whether_in_game(game_state) == false {
         return;
     }
     for status_ptr in query_entity_status.iter_mut() {
         let mut status: std::sync::MutexGuard<EntityStatus> = status_ptr.pointer.lock().unwrap();
-        let block_id = gamemap.query_block_by_xyz(status.position);
-        if block_id.unwrap_or(-1) < 0 {
+        let block_id = gamemap.query_block_by_xyz(status.position).unwrap_or(blocks::AIR);
+        if let Some((fluid, _level)) = block_registry.fluid(block_id) {
+            // Entities float in water and sink slowly in lava, losing vertical speed to drag.
+            // Falling blocks and items sink in both.
+            let buoyancy = match status.block {
+                Some(_) => -9.8,
+                None => fluid.buoyancy(),
+            };
+            status.velocity.y += buoyancy * TIME_STEP;
+            status.velocity.y *= 1. - fluid.drag() * TIME_STEP;
+        }
+        else if !block_registry.is_solid(block_id) {
             status.velocity += Vec3::new(0., -9.8 * TIME_STEP, 0.);
         }
         else {
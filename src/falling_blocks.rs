@@ -0,0 +1,199 @@
+//! Falling blocks
+//! ---
+//! Blocks flagged with `BlockProperties::falls`, like sand and gravel, fall when there is air or a fluid under them:
+//! a block update schedules a tick (see the `block_updates` module), and in the tick the block is removed
+//! and a falling block entity is spawned in its place. It falls with the physics of all entities
+//! (see the `entities` module).
+//!
+//! When a falling block reaches the block under it, it lands: it is placed as a block again on a full (solid) block,
+//! or else, e.g. on a plant or where it cannot be placed, it is dropped as an item.
+//! Falling blocks and items look like their block (see `chunk_mesh::build_block_mesh`).
+
+use crate::block_updates::{add, BlockWorld};
+use crate::*;
+use bevy::prelude::*;
+use std::sync::Arc;
+
+/// Plugin responsible for landing falling blocks, and showing entities that look like a block.
+pub struct FallingBlockPlugin;
+impl Plugin for FallingBlockPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            land_falling_blocks
+                .in_schedule(CoreSchedule::FixedUpdate)
+                .run_if(in_state(GameState::InGame)),
+        );
+        app.add_system(build_block_models.in_set(OnUpdate(GameState::InGame)));
+    }
+}
+
+/// Number of game ticks between a change under a block and its fall.
+const FALL_DELAY: u64 = 2;
+/// A falling block lands when it is this close to the block under it.
+const LANDING_HEIGHT: f32 = 0.25;
+
+const DOWN: (i32, i32, i32) = (0, -1, 0);
+
+/// A component for the shown model of a falling block or an item, made of the block with the string ID.
+/// Its mesh is added by `build_block_models`, once the block texture atlas is built.
+#[derive(Component)]
+pub struct BlockModel {
+    pub block: String,
+}
+
+/// Whether a falling block falls into the block: air, or a fluid.
+fn can_fall_into(block_registry: &blocks::BlockRegistry, block_id: i32) -> bool {
+    return block_id < 0 || block_registry.fluid(block_id).is_some();
+}
+
+/// An entity made of a block, at rest at the position.
+fn block_entity(entity_type: &str, block: &str, position: Vec3) -> entities::EntityStatus {
+    return entities::EntityStatus {
+        entity_type: entity_type.to_string(),
+        health: 1,
+        position,
+        rotation: 0.,
+        scaling: Vec3::new(1., 1., 1.),
+        velocity: Vec3::new(0., 0., 0.),
+        attack_cd: 0.,
+        block: Some(block.to_string()),
+    };
+}
+
+/// Schedule the fall of the block, if it can fall into the block under it.
+pub fn on_block_update(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    match world.block(add(position, DOWN)) {
+        Some(below) if can_fall_into(world.block_registry, below) => {
+            world.schedule_tick(position, FALL_DELAY);
+        }
+        _ => {} // Solid ground, the bottom of the world, or an unloaded chunk.
+    }
+}
+
+/// Turn the block into a falling block, if it can still fall.
+pub fn on_scheduled_tick(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    let block = match world
+        .block(position)
+        .and_then(|block_id| world.block_registry.get(block_id))
+    {
+        Some(block) if block.falls => block,
+        _ => return,
+    };
+    match world.block(add(position, DOWN)) {
+        Some(below) if can_fall_into(world.block_registry, below) => {}
+        _ => return,
+    }
+    let falling_block = block_entity(
+        "FallingBlock",
+        &block.name,
+        Vec3::new(
+            position.0 as f32 + 0.5,
+            position.1 as f32,
+            position.2 as f32 + 0.5,
+        ),
+    );
+    world.set(position, blocks::AIR);
+    world.spawn_entity(falling_block);
+}
+
+/**
+Land falling blocks that reach the block under them: place them, or drop them as items.
+Falling blocks that fall out of the world are removed.
+*/
+fn land_falling_blocks(
+    mut commands: Commands,
+    query_falling_blocks: Query<
+        (Entity, &entities::EntityStatusPointer),
+        With<entities::FallingBlock>,
+    >,
+    mut game_map: ResMut<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
+    mut light_map: ResMut<lighting::LightMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    mut block_updates: ResMut<block_updates::BlockUpdates>,
+) {
+    let mut landed = vec![];
+    let mut world = BlockWorld {
+        game_map: &game_map,
+        block_registry: &block_registry,
+        light_map: &mut light_map,
+        chunk_meshes: &mut chunk_meshes,
+        block_updates: &mut block_updates,
+    };
+    for (entity, status_ptr) in query_falling_blocks.iter() {
+        let status = status_ptr.pointer.lock().unwrap();
+        let block = status
+            .block
+            .as_ref()
+            .and_then(|block_name| Some((block_name.clone(), block_registry.id(block_name)?)));
+        let (block_name, block_id) = match block {
+            Some(block) if status.position.y >= 0. => block,
+            _ => {
+                // Out of the world, or an unknown block.
+                landed.push((entity, Arc::clone(&status_ptr.pointer)));
+                continue;
+            }
+        };
+        let cell = (
+            status.position.x.floor() as i32,
+            status.position.y.floor() as i32,
+            status.position.z.floor() as i32,
+        );
+        let below = match world.block(add(cell, DOWN)) {
+            Some(below) => below,
+            None => continue, // An unloaded chunk.
+        };
+        let next_y = status.position.y + status.velocity.y * entities::TIME_STEP;
+        if can_fall_into(&block_registry, below)
+            || (status.position.y - cell.1 as f32 >= LANDING_HEIGHT && next_y >= cell.1 as f32)
+        {
+            continue;
+        }
+        landed.push((entity, Arc::clone(&status_ptr.pointer)));
+        let here = world.block(cell).unwrap_or(blocks::AIR);
+        if !(block_registry.is_solid(below)
+            && can_fall_into(&block_registry, here)
+            && world.set(cell, block_id))
+        {
+            world.spawn_entity(block_entity("Item", &block_name, status.position));
+        }
+    }
+    for (entity, status_ptr) in landed {
+        commands.entity(entity).despawn_recursive();
+        for chunk in game_map.map.values_mut() {
+            chunk
+                .entities
+                .retain(|chunk_entity| !Arc::ptr_eq(chunk_entity, &status_ptr));
+        }
+    }
+}
+
+/// Add the mesh of their block to new models of falling blocks and items.
+fn build_block_models(
+    mut commands: Commands,
+    query_block_models: Query<(Entity, &BlockModel), Without<Handle<Mesh>>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    game_assets: Res<init_game::GameAssets>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    for (entity, block_model) in query_block_models.iter() {
+        let block = match block_registry
+            .id(&block_model.block)
+            .and_then(|block_id| block_registry.get(block_id))
+        {
+            Some(block) => block,
+            None => {
+                println!("Unknown block of an entity: {}", block_model.block);
+                commands.entity(entity).despawn();
+                continue;
+            }
+        };
+        commands.entity(entity).insert((
+            meshes.add(chunk_mesh::build_block_mesh(
+                block,
+                &game_assets.block_texture_rects,
+            )),
+            game_assets.block_material.clone(),
+        ));
+    }
+}
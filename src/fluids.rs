@@ -0,0 +1,208 @@
+//! Fluids
+//! ---
+//! Water and lava are blocks with a level: a source block has level 0, and a flowing block has the distance
+//! it has flowed from a source, up to `Fluid::max_level`. Each level is a separate block (see `Fluid::block_name`).
+//!
+//! Fluids move in scheduled ticks, some time after a block next to them changes
+//! (see the `block_updates` module). In a tick, a fluid block:
+//! - hardens if it is lava touching water: a lava source becomes obsidian, and flowing lava cobblestone;
+//! - if it is flowing, takes one more than the lowest level beside it (or 1 below a fluid),
+//!   and dries up if nothing feeds it. Water between two sources over solid ground becomes a source.
+//! - flows down if it can, or else spreads sideways with one level more.
+//!
+//! Fluids flow into air and wash away non-solid blocks like plants.
+//! Entities float in water and sink slowly in lava, and move slower in both (see the `entities` module).
+
+use crate::block_updates::{add, BlockWorld};
+use crate::*;
+use bevy::prelude::*;
+
+/// The color of water. Water textures are gray.
+pub const WATER_TINT: Color = Color::rgb(0.25, 0.42, 0.85);
+
+const UP: (i32, i32, i32) = (0, 1, 0);
+const DOWN: (i32, i32, i32) = (0, -1, 0);
+const SIDES: [(i32, i32, i32); 4] = [(1, 0, 0), (-1, 0, 0), (0, 0, 1), (0, 0, -1)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Fluid {
+    Water,
+    Lava,
+}
+
+impl Fluid {
+    /// The highest level of flowing blocks, i.e. how far the fluid flows on flat ground.
+    pub fn max_level(&self) -> u8 {
+        return match self {
+            Fluid::Water => 7,
+            Fluid::Lava => 3,
+        };
+    }
+
+    /// The string ID of the block of the fluid at the level.
+    pub fn block_name(&self, level: u8) -> String {
+        let name = match self {
+            Fluid::Water => "water",
+            Fluid::Lava => "lava",
+        };
+        return match level {
+            0 => name.to_string(),
+            _ => format!("flowing_{}_{}", name, level),
+        };
+    }
+
+    /// The numeric ID of the block of the fluid at the level.
+    pub fn block_id(&self, level: u8, block_registry: &blocks::BlockRegistry) -> i32 {
+        return block_registry.id(&self.block_name(level)).unwrap();
+    }
+
+    /// Number of game ticks between a change next to the fluid and its tick. Lava is slower.
+    fn tick_delay(&self) -> u64 {
+        return match self {
+            Fluid::Water => 15,
+            Fluid::Lava => 90,
+        };
+    }
+
+    /// Vertical acceleration of entities in the fluid, with gravity: upward in water, downward in lava.
+    pub fn buoyancy(&self) -> f32 {
+        return match self {
+            Fluid::Water => 1.5,
+            Fluid::Lava => -2.,
+        };
+    }
+
+    /// How much of their vertical speed entities lose per second in the fluid.
+    pub fn drag(&self) -> f32 {
+        return match self {
+            Fluid::Water => 2.,
+            Fluid::Lava => 4.,
+        };
+    }
+
+    /// How fast entities move in the fluid, relative to their speed outside.
+    pub fn movement_factor(&self) -> f32 {
+        return match self {
+            Fluid::Water => 0.5,
+            Fluid::Lava => 0.3,
+        };
+    }
+}
+
+/// The fluid at a position, if any.
+pub fn fluid_at(
+    game_map: &gamemap::GameMap,
+    position: Vec3,
+    block_registry: &blocks::BlockRegistry,
+) -> Option<Fluid> {
+    let block_id = game_map.query_block_by_xyz(position)?;
+    return block_registry.fluid(block_id).map(|(fluid, _level)| fluid);
+}
+
+/// The fluid and level of the block at the position, if it is a fluid.
+fn fluid(world: &BlockWorld, position: (i32, i32, i32)) -> Option<(Fluid, u8)> {
+    return world
+        .block(position)
+        .and_then(|block_id| world.block_registry.fluid(block_id));
+}
+
+/// Whether a fluid can flow into the block: air, or a non-solid block that is not a fluid.
+fn can_flow_into(world: &BlockWorld, block_id: i32) -> bool {
+    return block_id < 0
+        || (!world.block_registry.is_solid(block_id)
+            && world.block_registry.fluid(block_id).is_none());
+}
+
+/// Schedule a tick of the fluid block, after it or a block next to it has changed.
+pub fn on_block_update(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    if let Some((fluid, _level)) = fluid(world, position) {
+        world.schedule_tick(position, fluid.tick_delay());
+    }
+}
+
+/// Do a fluid tick at the position. See the module documentation.
+pub fn on_scheduled_tick(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    let (this_fluid, mut level) = match fluid(world, position) {
+        Some(fluid_level) => fluid_level,
+        None => return,
+    };
+
+    if this_fluid == Fluid::Lava
+        && block_updates::NEIGHBORS
+            .iter()
+            .any(|&offset| matches!(fluid(world, add(position, offset)), Some((Fluid::Water, _))))
+    {
+        let hardened = match level {
+            0 => "obsidian",
+            _ => "cobblestone",
+        };
+        world.set(position, world.block_registry.id(hardened).unwrap());
+        return;
+    }
+
+    if level > 0 {
+        let mut new_level = this_fluid.max_level() + 1;
+        let mut sources = 0;
+        if matches!(fluid(world, add(position, UP)), Some((above, _)) if above == this_fluid) {
+            new_level = 1;
+        }
+        for offset in SIDES {
+            if let Some((side_fluid, side_level)) = fluid(world, add(position, offset)) {
+                if side_fluid == this_fluid {
+                    new_level = new_level.min(side_level + 1);
+                    sources += (side_level == 0) as usize;
+                }
+            }
+        }
+        let below = world.block(add(position, DOWN)).unwrap_or(blocks::AIR);
+        if this_fluid == Fluid::Water
+            && sources >= 2
+            && (world.block_registry.is_solid(below)
+                || world.block_registry.fluid(below) == Some((this_fluid, 0)))
+        {
+            new_level = 0;
+        }
+        if new_level > this_fluid.max_level() {
+            world.set(position, blocks::AIR);
+            return;
+        }
+        if new_level != level {
+            level = new_level;
+            world.set(position, this_fluid.block_id(level, world.block_registry));
+        }
+    }
+
+    let below = match world.block(add(position, DOWN)) {
+        Some(below) => below,
+        None => return, // The bottom of the world, or an unloaded chunk.
+    };
+    if can_flow_into(world, below) {
+        world.set(
+            add(position, DOWN),
+            this_fluid.block_id(1, world.block_registry),
+        );
+        return;
+    }
+    if world.block_registry.fluid(below).is_some() || level >= this_fluid.max_level() {
+        return; // Fluids do not spread sideways over fluids.
+    }
+    let spread_block = this_fluid.block_id(level + 1, world.block_registry);
+    for offset in SIDES {
+        let side = add(position, offset);
+        match world.block(side) {
+            Some(side_block) if can_flow_into(world, side_block) => {
+                world.set(side, spread_block);
+            }
+            Some(side_block) => match world.block_registry.fluid(side_block) {
+                // A farther flowing block of the fluid is fed by this one now.
+                Some((side_fluid, side_level))
+                    if side_fluid == this_fluid && side_level > level + 1 =>
+                {
+                    world.set(side, spread_block);
+                }
+                _ => {}
+            },
+            None => {}
+        }
+    }
+}
@@ -1,16 +1,44 @@
 use crate::*;
 use bevy::prelude::*;
-use noise::*;
 use rand::*;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use std::cmp::min;
 use std::f32::consts::PI;
+use std::path::Path;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
+/**
+Settings for creating a new world. This is a global resource.
+The seed, the world generator, the height and the random tick speed can be given on the command line:
+`rust_mc --seed <number or text> --generator <generator> --height <blocks> --random-tick-speed <number>`
+(see `worldgen::GeneratorSettings::parse`).
+*/
+#[derive(Resource)]
+pub struct NewWorldSettings {
+    /// The world seed of new worlds. A random seed is used if None.
+    pub seed: Option<u64>,
+    /// The world generator of new worlds.
+    pub generator: worldgen::GeneratorSettings,
+    /// The height of new worlds in blocks, a multiple of `sections::SECTION_HEIGHT`.
+    pub height: usize,
+    /// The random tick speed of new worlds (see `GameMap::random_tick_speed`).
+    pub random_tick_speed: u32,
+}
+
+impl Default for NewWorldSettings {
+    fn default() -> Self {
+        NewWorldSettings {
+            seed: None,
+            generator: worldgen::GeneratorSettings::default(),
+            height: DEFAULT_WORLD_HEIGHT,
+            random_tick_speed: DEFAULT_RANDOM_TICK_SPEED,
+        }
+    }
+}
+
 /// The static variable representing the world's name.
 /// At the start of the main menu, it is None.
 /// It is initialized when choosing the world.
@@ -19,17 +47,86 @@ pub struct WorldName {
     pub name: Option<String>,
 }
 
+/**
+The reason why a world failed to load.
+The world's save is left untouched, so that loading can be retried or a backup can be opened.
+*/
+#[derive(Debug)]
+pub enum WorldLoadError {
+    /// The save could not be read, e.g. missing permissions.
+    Io(std::io::Error),
+    /// The save was read, but is malformed.
+    Malformed(String),
+}
+
+impl std::fmt::Display for WorldLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WorldLoadError::Io(err) => write!(f, "failed to read the save: {}", err),
+            WorldLoadError::Malformed(message) => write!(f, "the save is malformed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for WorldLoadError {}
+
+impl From<std::io::Error> for WorldLoadError {
+    fn from(err: std::io::Error) -> Self {
+        return match err.kind() {
+            // Region files report broken data as these kinds.
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                WorldLoadError::Malformed(err.to_string())
+            }
+            _ => WorldLoadError::Io(err),
+        };
+    }
+}
+
+impl From<serde_json::Error> for WorldLoadError {
+    fn from(err: serde_json::Error) -> Self {
+        return match err.classify() {
+            serde_json::error::Category::Io => WorldLoadError::Io(err.into()),
+            _ => WorldLoadError::Malformed(err.to_string()),
+        };
+    }
+}
+
+/// The error of the last attempt to load the world, shown by the load failed UI. This is a global resource.
+#[derive(Resource, Default)]
+pub struct WorldLoadFailure {
+    pub error: Option<WorldLoadError>,
+}
+
 pub const CHUNK_SIZE: usize = 16;
-pub const CHUNK_HEIGHT: usize = 32;
-/// Number of CHUNKS in a row.
-pub const CHUNK_LEN: usize = 6;
+/// The height of new worlds, unless another height is chosen.
+pub const DEFAULT_WORLD_HEIGHT: usize = 128;
+/// The highest world that can be created.
+pub const MAX_WORLD_HEIGHT: usize = 512;
+/// The height of worlds created before the height was configurable.
+pub const LEGACY_WORLD_HEIGHT: usize = 32;
+
+fn legacy_world_height() -> usize {
+    return LEGACY_WORLD_HEIGHT;
+}
+
+/// The random tick speed of new worlds, and of worlds created before it was configurable.
+pub const DEFAULT_RANDOM_TICK_SPEED: u32 = 1;
+/**
+The highest random tick speed. Random ticks of all sections of loaded chunks are done in every game tick,
+60 times a second, so higher speeds would stall the game.
+*/
+pub const MAX_RANDOM_TICK_SPEED: u32 = 256;
+
+fn default_random_tick_speed() -> u32 {
+    return DEFAULT_RANDOM_TICK_SPEED;
+}
 
 /// The whole game map. Contains all blocks and entities.
 /// Can be serialized and stored into a file, and deserialized from a file.
 ///
 /// Usage:
 /// ```
-/// let mut game_map: GameMap = new_gamemap();
+/// let mut game_map: GameMap = new_gamemap(seed, generator_settings, height, random_tick_speed, &block_registry);
 /// let chunks_x = 1;
 /// let chunks_z = 2;
 /// game_map.map.get((chunks_x, chunks_z)) // is a Chunk
@@ -39,9 +136,119 @@ pub const CHUNK_LEN: usize = 6;
 pub struct GameMap {
     #[serde_as(as = "Vec<(_,_)>")]
     pub map: HashMap<(i32, i32), Chunk>,
+    /// The string IDs of numeric block IDs used in `map`, indexed by numeric ID.
+    /// Written by `save_gamemap`, and used by `load_gamemap` to remap block IDs.
+    #[serde(default)]
+    pub block_palette: Vec<String>,
+    /// The world seed. Everything generated in the world is derived from it.
+    #[serde(default)]
+    pub seed: u64,
+    /// Seeds of the terrain noise, used to generate new chunks. Derived from `seed` by `terrain_seeds_from_seed`,
+    /// except in worlds created before world seeds existed, which keep their random terrain seeds.
+    #[serde(default)]
+    pub terrain_seeds: [u32; 3],
+    /// The world generator of new chunks, chosen when the world is created.
+    #[serde(default)]
+    pub generator: worldgen::GeneratorSettings,
+    /// The height of the world in blocks, chosen when the world is created. Every chunk has this height.
+    #[serde(default = "legacy_world_height")]
+    pub height: usize,
+    /// The world time in seconds, kept up to date by the `daycycle` module while in game.
+    #[serde(default)]
+    pub time: f64,
+    /// The weather, kept up to date by the `weather` module while in game.
+    #[serde(default)]
+    pub weather: weather::Weather,
+    /// Number of game ticks done in the world, counted by the `block_updates` module.
+    #[serde(default)]
+    pub tick: u64,
+    /// Number of random blocks of each chunk section that get a random tick in each game tick,
+    /// chosen when the world is created. 0 stops random ticks. See the `block_updates` module.
+    #[serde(default = "default_random_tick_speed")]
+    pub random_tick_speed: u32,
 }
 
 impl GameMap {
+    /// Generate the chunk at the chunk coordinates with random creatures,
+    /// and insert it into the map. Does nothing if the chunk already exists.
+    pub fn generate_chunk(&mut self, chunk_key: (i32, i32), block_registry: &blocks::BlockRegistry) {
+        if self.map.contains_key(&chunk_key) {
+            return;
+        }
+        let generator = self.generator.create(self.seed, self.terrain_seeds, self.height, block_registry);
+        let blocks = generator.generate_blocks(chunk_key);
+        let heightmap = surface_heightmap(&blocks);
+        let chunk = Chunk {
+            blocks: Arc::new(Mutex::new(blocks)),
+            entities: random_creatures(chunk_key, self.seed, &heightmap),
+            scheduled_ticks: Mutex::new(Vec::new()),
+        };
+        self.map.insert(chunk_key, chunk);
+    }
+    /// Load the chunk at the chunk coordinates from the world's save folder,
+    /// or generate it if it has never been saved. Does nothing if the chunk is already loaded.
+    pub fn load_chunk(
+        &mut self,
+        chunk_key: (i32, i32),
+        world_dir: &Path,
+        block_registry: &blocks::BlockRegistry,
+    ) -> std::io::Result<()> {
+        if self.map.contains_key(&chunk_key) {
+            return Ok(());
+        }
+        match region::load_chunk(world_dir, chunk_key)? {
+            Some(chunk) => {
+                self.map.insert(chunk_key, chunk);
+            }
+            None => self.generate_chunk(chunk_key, block_registry),
+        }
+        return Ok(());
+    }
+    /// Save the chunk at the chunk coordinates to the world's save folder, and remove it from the map.
+    /// The chunk stays loaded if it fails to be saved.
+    pub fn unload_chunk(&mut self, chunk_key: (i32, i32), world_dir: &Path) -> std::io::Result<Chunk> {
+        region::save_chunk(world_dir, chunk_key, &self.map[&chunk_key])?;
+        return Ok(self.map.remove(&chunk_key).unwrap());
+    }
+    /// Find the chunk that the main player is in.
+    pub fn find_main_player_chunk(&self) -> Option<(i32, i32)> {
+        for (&chunk_key, chunk) in self.map.iter() {
+            for entity in chunk.entities.iter() {
+                if entity.lock().unwrap().entity_type == "MainPlayer" {
+                    return Some(chunk_key);
+                }
+            }
+        }
+        return None;
+    }
+    /**
+    Move every entity into the loaded chunk that contains its position,
+    so that entities are saved and unloaded together with the chunk they are in.
+    Entities outside of loaded chunks stay in their old chunks.
+    */
+    pub fn update_entity_chunks(&mut self) {
+        let loaded_chunk_keys: HashSet<(i32, i32)> = self.map.keys().copied().collect();
+        let mut moved_entities = vec![];
+        for (&chunk_key, chunk) in self.map.iter_mut() {
+            let mut staying_entities = vec![];
+            for entity in chunk.entities.drain(..) {
+                let position = entity.lock().unwrap().position;
+                let new_chunk_key = (
+                    (position.x.floor() as i32).div_euclid(CHUNK_SIZE as i32),
+                    (position.z.floor() as i32).div_euclid(CHUNK_SIZE as i32),
+                );
+                if new_chunk_key != chunk_key && loaded_chunk_keys.contains(&new_chunk_key) {
+                    moved_entities.push((new_chunk_key, entity));
+                } else {
+                    staying_entities.push(entity);
+                }
+            }
+            chunk.entities = staying_entities;
+        }
+        for (new_chunk_key, entity) in moved_entities {
+            self.map.get_mut(&new_chunk_key).unwrap().entities.push(entity);
+        }
+    }
     /// Query a position's chunk that it belongs.
     pub fn query_chunk_by_xyz(&self, xyz: Vec3) -> (i32, i32) {
         let x = xyz[0].floor() as i32;
@@ -50,268 +257,573 @@ impl GameMap {
         let chunk_z = z.div_euclid(16);
         return (chunk_x, chunk_z);
     }
-    /// Converts a block's position to index in chunk if it exists.
+    /**
+    Converts a block's position to index in chunk if it exists.
+    Returns None below y = 0, above the world, or in a chunk that is not loaded.
+    */
     pub fn to_integer(&self, xyz: Vec3) -> Option<(usize, usize, usize)> {
         let x = xyz[0].floor() as i32;
-        let y = xyz[1].floor() as usize;
+        let y = xyz[1].floor();
         let z = xyz[2].floor() as i32;
         let chunk_x = x.div_euclid(16);
         let chunk_z = z.div_euclid(16);
         let newx: usize = (x - 16 * chunk_x).try_into().unwrap();
         let newz: usize = (z - 16 * chunk_z).try_into().unwrap();
-        if y >= CHUNK_HEIGHT {
+        // Also false for NaN.
+        if !(y >= 0. && y < self.height as f32) {
             return None;
         }
         return match self.map.get(&(chunk_x, chunk_z)) {
-            Some(_chunk) => Some((newx, y, newz)),
+            Some(_chunk) => Some((newx, y as usize, newz)),
             None => None,
         };
     }
     /// Query a block according to the coordinates.
+    /// Returns None below y = 0, above the world, or in a chunk that is not loaded.
     pub fn query_block_by_xyz(&self, xyz: Vec3) -> Option<i32> {
-        let x = xyz[0].floor() as i32;
-        let y = xyz[1].floor() as usize;
-        let z = xyz[2].floor() as i32;
-        let chunk_x = x.div_euclid(16);
-        let chunk_z = z.div_euclid(16);
-        let newx: usize = (x - 16 * chunk_x).try_into().unwrap();
-        let newz: usize = (z - 16 * chunk_z).try_into().unwrap();
-        if y >= CHUNK_HEIGHT {
+        let (x, y, z) = self.to_integer(xyz)?;
+        let chunk = &self.map[&self.query_chunk_by_xyz(xyz)];
+        return Some(chunk.blocks.lock().unwrap().get(x, y, z));
+    }
+    /// Query the block at integer world coordinates.
+    /// Returns None below y = 0, above the world, or in a chunk that is not loaded.
+    pub fn block_at(&self, (x, y, z): (i32, i32, i32)) -> Option<i32> {
+        if y < 0 || y >= self.height as i32 {
             return None;
         }
-        return match self.map.get(&(chunk_x, chunk_z)) {
-            Some(chunk) => Some(chunk.blocks.lock().unwrap()[newx][y][newz]),
-            None => None,
+        let size = CHUNK_SIZE as i32;
+        let chunk = self.map.get(&(x.div_euclid(size), z.div_euclid(size)))?;
+        let blocks = chunk.blocks.lock().unwrap();
+        return Some(blocks.get(
+            x.rem_euclid(size) as usize,
+            y as usize,
+            z.rem_euclid(size) as usize,
+        ));
+    }
+    /**
+    Set the block at integer world coordinates. Only the blocks are changed:
+    the caller updates light and chunk meshes.
+    Returns whether the block was set (false below y = 0, above the world, or in a chunk that is not loaded).
+    */
+    pub fn set_block_at(&self, (x, y, z): (i32, i32, i32), block_id: i32) -> bool {
+        if y < 0 || y >= self.height as i32 {
+            return false;
+        }
+        let size = CHUNK_SIZE as i32;
+        return match self.map.get(&(x.div_euclid(size), z.div_euclid(size))) {
+            Some(chunk) => chunk.blocks.lock().unwrap().set(
+                x.rem_euclid(size) as usize,
+                y as usize,
+                z.rem_euclid(size) as usize,
+                block_id,
+            ),
+            None => false,
         };
     }
 }
 
-type ChunkBlocks = [[[i32; CHUNK_SIZE]; CHUNK_HEIGHT]; CHUNK_SIZE];
-
-type ChunkBlocksXZ = [[usize; CHUNK_SIZE]; CHUNK_SIZE];
+/// A value for each column of a chunk, indexed by `[x][z]`.
+pub type ChunkBlocksXZ = [[usize; CHUNK_SIZE]; CHUNK_SIZE];
 
 /// A Chunk is blocks within a 16*height*16 region, with all entities in this region.
 /// Both blocks and entities are stored as `Arc<Mutex<...>>`.
 #[derive(Serialize, Deserialize)]
 pub struct Chunk {
-    pub blocks: Arc<Mutex<ChunkBlocks>>,
+    pub blocks: Arc<Mutex<sections::ChunkBlocks>>,
     pub entities: Vec<Arc<Mutex<entities::EntityStatus>>>,
+    /// Ticks scheduled at blocks of this chunk. See the `block_updates` module.
+    #[serde(default)]
+    pub scheduled_ticks: Mutex<Vec<block_updates::ScheduledTick>>,
 }
 
 /**
-Returns a chunk with random height at each position, no entities inside the chunk.
-Use Berlin Noise with different freqencies and amplitude to show different terrains.
- */
-fn random_chunk(xx: usize, zz: usize, seed1: u32, seed2: u32, seed3: u32) -> Chunk {
-    let mut blocks: ChunkBlocks = Default::default();
-    let mut height: ChunkBlocksXZ = Default::default();
-    //let normal = Normal::new(4.0, 1.0).unwrap();
-    let noise1 = Perlin::new(seed1);
-    let noise2 = Perlin::new(seed2);
-    let noise3 = Perlin::new(seed3);
-    let world_size = (CHUNK_LEN * CHUNK_SIZE) as f64;
-    // let xx = 0;
-    // let zz = 0;
-
+Returns the surface heightmap of a chunk: for each column, one above its highest non-air block
+(0 if the whole column is air). Things spawn on the surface.
+*/
+pub fn surface_heightmap(blocks: &sections::ChunkBlocks) -> ChunkBlocksXZ {
+    let mut heightmap: ChunkBlocksXZ = Default::default();
     for x in 0..CHUNK_SIZE {
         for z in 0..CHUNK_SIZE {
-            let noise_1 = CHUNK_HEIGHT as f64 / 2.0
-                * noise1.get([
-                    (x + xx * CHUNK_SIZE) as f64 / world_size,
-                    (z + zz * CHUNK_SIZE) as f64 / world_size,
-                ]);
-            let noise_2 = CHUNK_HEIGHT as f64 / 4.0
-                * noise2.get([
-                    ((x + xx * CHUNK_SIZE) as f64 / world_size * 3.0).fract(),
-                    ((z + zz * CHUNK_SIZE) as f64 / world_size * 3.0).fract(),
-                ]);
-            let noise_3 = CHUNK_HEIGHT as f64 / 8.0
-                * noise3.get([
-                    ((x + xx * CHUNK_SIZE) as f64 / world_size * 7.0).fract(),
-                    ((z + zz * CHUNK_SIZE) as f64 / world_size * 7.0).fract(),
-                ]);
-            height[x][z] = (noise_1 + noise_2 + noise_3) as usize + CHUNK_HEIGHT / 2;
-            height[x][z] = usize::max(1, min(CHUNK_HEIGHT, height[x][z]));
-
-            for y in 0..height[x][z] - 1 {
-                blocks[x][y][z] = 210;
-            }
-            blocks[x][height[x][z] - 1][z] = 361;
-            for y in height[x][z]..CHUNK_HEIGHT {
-                blocks[x][y][z] = -1;
-            }
+            heightmap[x][z] = (0..blocks.height())
+                .rev()
+                .find(|&y| blocks.get(x, y, z) != blocks::AIR)
+                .map_or(0, |y| y + 1);
         }
     }
-    return Chunk {
-        blocks: Arc::new(Mutex::new(blocks)),
-        entities: vec![],
-    };
+    return heightmap;
 }
 
 /**
-Returns a flat chunk (half stone, half air) with no entities.
+Returns some random creatures standing on the surface of the chunk (given by its heightmap), or no creatures.
+Creatures are not spawned above columns without any block.
 */
-fn flat_chunk() -> Chunk {
-    let mut blocks: ChunkBlocks = Default::default();
-    for y in 0..CHUNK_HEIGHT / 2 {
-        for x in 0..CHUNK_SIZE {
-            for z in 0..CHUNK_SIZE {
-                blocks[x][y][z] = 210;
+fn random_creatures(
+    chunk_key: (i32, i32),
+    seed: u64,
+    heightmap: &ChunkBlocksXZ,
+) -> Vec<Arc<Mutex<entities::EntityStatus>>> {
+    let (x, z) = chunk_key;
+    let mut rng = rngs::StdRng::seed_from_u64(chunk_seed(seed, chunk_key, 0));
+    let mut creatures = vec![];
+    if rng.gen::<f32>() < 0.5 {
+        for (entity_type, health, rotation) in [
+            ("Creeper", 20, PI * 0.75),
+            ("Player", 20, PI * 1.5),
+            ("HuTao", 10000, PI * 0.25),
+            ("Chicken", 20, PI * 0.25),
+        ] {
+            let local_x = rng.gen::<f32>() * CHUNK_SIZE as f32;
+            let local_z = rng.gen::<f32>() * CHUNK_SIZE as f32;
+            let proper_y = heightmap[local_x as usize][local_z as usize] as f32;
+            if proper_y == 0. {
+                continue;
             }
+            creatures.push(Arc::new(Mutex::new(entities::EntityStatus {
+                entity_type: entity_type.to_string(),
+                health: health,
+                position: Vec3::new(
+                    (x * CHUNK_SIZE as i32) as f32 + local_x,
+                    proper_y,
+                    (z * CHUNK_SIZE as i32) as f32 + local_z,
+                ),
+                rotation: rotation,
+                scaling: Vec3::new(1., 1., 1.),
+                velocity: Vec3::new(0., 0., 0.),
+                attack_cd: 0.,
+                block: None,
+            })));
         }
     }
-    for y in CHUNK_HEIGHT / 2..CHUNK_HEIGHT {
-        for x in 0..CHUNK_SIZE {
-            for z in 0..CHUNK_SIZE {
-                blocks[x][y][z] = -1;
+    return creatures;
+}
+
+/**
+Derive a seed for one purpose from the world seed, so that different purposes get unrelated random numbers.
+This is SplitMix64, which gives the same results on every machine.
+*/
+pub fn derive_seed(seed: u64, salt: u64) -> u64 {
+    let mut z = seed.wrapping_add(salt.wrapping_add(1).wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    return z ^ (z >> 31);
+}
+
+/// Derive a seed for one purpose (`salt`) in one chunk from the world seed.
+pub fn chunk_seed(seed: u64, chunk_key: (i32, i32), salt: u64) -> u64 {
+    let chunk_salt = ((chunk_key.0 as u32 as u64) << 32) | chunk_key.1 as u32 as u64;
+    return derive_seed(derive_seed(seed, salt), chunk_salt);
+}
+
+/// Seeds of the terrain noise of a world seed.
+pub fn terrain_seeds_from_seed(seed: u64) -> [u32; 3] {
+    return [
+        derive_seed(seed, 0) as u32,
+        derive_seed(seed, 1) as u32,
+        derive_seed(seed, 2) as u32,
+    ];
+}
+
+/**
+Turn a seed typed by the player into a world seed.
+A number is used as it is. Any other text is hashed (with FNV-1a, which is the same on every machine).
+*/
+pub fn seed_from_text(text: &str) -> u64 {
+    let text = text.trim();
+    if let Ok(seed) = text.parse::<u64>() {
+        return seed;
+    }
+    if let Ok(seed) = text.parse::<i64>() {
+        return seed as u64;
+    }
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+/**
+A new game map with only the middle chunk, and the main player in it.
+Other chunks are generated by the `chunk_loading` module as the player approaches.
+*/
+pub fn new_gamemap(
+    seed: u64,
+    generator: worldgen::GeneratorSettings,
+    height: usize,
+    random_tick_speed: u32,
+    block_registry: &blocks::BlockRegistry,
+) -> GameMap {
+    let mut new_map = GameMap {
+        map: HashMap::new(),
+        block_palette: block_registry.palette(),
+        seed,
+        terrain_seeds: terrain_seeds_from_seed(seed),
+        generator,
+        height,
+        time: daycycle::NEW_WORLD_TIME,
+        // New worlds start with a day of clear weather.
+        weather: weather::Weather {
+            kind: weather::WeatherKind::Clear,
+            remaining: daycycle::DAY_LENGTH,
+        },
+        tick: 0,
+        random_tick_speed,
+    };
+    new_map.generate_chunk((0, 0), block_registry);
+    let spawn_chunk = new_map.map.get_mut(&(0, 0)).unwrap();
+    let spawn_height = surface_heightmap(&spawn_chunk.blocks.lock().unwrap())[0][0];
+    spawn_chunk
+        .entities
+        .push(Arc::new(Mutex::new(entities::EntityStatus {
+            entity_type: "MainPlayer".to_string(),
+            health: 20,
+            position: Vec3::new(0., spawn_height as f32, 0.),
+            rotation: PI * 0.0,
+            scaling: Vec3::new(1., 1., 1.),
+            velocity: Vec3::new(0., 0., 0.),
+            attack_cd: 0.,
+            block: None,
+        })));
+    return new_map;
+}
+
+/**
+Numeric block IDs of worlds saved before blocks had string IDs, when they were indices into the block texture folder.
+Worlds saved then have no block palette. Only these blocks were ever generated.
+*/
+const LEGACY_BLOCK_IDS: [(i32, &str); 2] = [(210, "stone"), (361, "grass_block")];
+
+/**
+The block palette of worlds saved without one (see `LEGACY_BLOCK_IDS`).
+Numeric IDs not in `LEGACY_BLOCK_IDS` have an empty name, so they are unknown blocks.
+*/
+pub fn legacy_block_palette() -> Vec<String> {
+    let len = LEGACY_BLOCK_IDS.iter().map(|&(id, _)| id as usize + 1).max().unwrap();
+    let mut palette = vec![String::new(); len];
+    for (id, name) in LEGACY_BLOCK_IDS {
+        palette[id as usize] = name.to_string();
+    }
+    return palette;
+}
+
+/// The block palette of saved chunks: `palette`, or the legacy palette if the save has none.
+fn saved_block_palette(palette: &Vec<String>) -> Vec<String> {
+    if palette.is_empty() {
+        return legacy_block_palette();
+    }
+    return palette.clone();
+}
+
+/// Whether saved chunks with the block palette (empty if the save has none) must be remapped to the block registry.
+fn needs_remapping(palette: &Vec<String>, block_registry: &blocks::BlockRegistry) -> bool {
+    return palette.is_empty() || !block_registry.palette().starts_with(palette);
+}
+
+/// The numeric ID in the block palette `to` of each block of the block palette `from`, None if it is not in `to`.
+fn palette_mapping(from: &Vec<String>, to: &Vec<String>) -> Vec<Option<i32>> {
+    let to_ids: HashMap<&String, i32> = to.iter().enumerate().map(|(id, name)| (name, id as i32)).collect();
+    return from
+        .iter()
+        .map(|name| if name.is_empty() { None } else { to_ids.get(name).copied() })
+        .collect();
+}
+
+/**
+Remap numeric block IDs in a chunk from a block palette, with `new_ids` from `palette_mapping`.
+Blocks without a new ID are replaced with air,
+and counted in `unknown_blocks` by name (or by numeric ID, if the palette has no name for it).
+*/
+fn remap_block_ids(
+    chunk: &Chunk,
+    palette: &Vec<String>,
+    new_ids: &Vec<Option<i32>>,
+    unknown_blocks: &mut HashMap<String, usize>,
+) {
+    let mut chunk_blocks = chunk.blocks.lock().unwrap();
+    chunk_blocks.map_blocks(|block_id, count| {
+        if block_id < 0 {
+            return block_id;
+        }
+        return match new_ids.get(block_id as usize) {
+            Some(&Some(new_id)) => new_id,
+            _ => {
+                let name = match palette.get(block_id as usize) {
+                    Some(name) if !name.is_empty() => name.clone(),
+                    _ => format!("#{}", block_id),
+                };
+                if count > 0 {
+                    *unknown_blocks.entry(name).or_default() += count;
+                }
+                blocks::AIR
             }
+        };
+    });
+}
+
+/**
+Remap numeric block IDs in all chunks of a game map from its block palette to the block registry,
+and set its palette to the registry's. Returns the unknown blocks, like `upgrade_block_palette`.
+*/
+pub fn remap_gamemap(
+    gamemap: &mut GameMap,
+    block_registry: &blocks::BlockRegistry,
+) -> HashMap<String, usize> {
+    let mut unknown_blocks: HashMap<String, usize> = HashMap::new();
+    if needs_remapping(&gamemap.block_palette, block_registry) {
+        let palette = saved_block_palette(&gamemap.block_palette);
+        let new_ids = palette_mapping(&palette, &block_registry.palette());
+        for chunk in gamemap.map.values() {
+            remap_block_ids(chunk, &palette, &new_ids, &mut unknown_blocks);
         }
     }
-    return Chunk {
-        blocks: Arc::new(Mutex::new(blocks)),
-        entities: vec![],
-    };
+    gamemap.block_palette = block_registry.palette();
+    return unknown_blocks;
+}
+
+/// Report blocks replaced with air because they are unknown to the block registry.
+pub fn report_unknown_blocks(unknown_blocks: HashMap<String, usize>) {
+    for (name, count) in unknown_blocks {
+        println!("Unknown block \"{}\" in save, replaced {} blocks with air", name, count);
+    }
 }
 
 /**
-A test game map, with 6*6 flat chunks, with some entities in the middle chunk.
+Rewrite all saved chunks of a world from its block palette to the block registry,
+if the palette does not match the registry's numeric IDs, and set the palette of `level_data` to the registry's.
+Returns the unknown block names with the number of blocks replaced with air.
+
+A world without a palette was saved before palettes existed, and uses `legacy_block_palette`.
+The new palette is saved in `level.json` before any chunk is rewritten, together with the old palette,
+and every rewritten region is recorded there too (see `Remapping`).
+So a remapping that fails halfway is resumed at the next load, and no chunk is ever remapped twice.
 */
-pub fn new_gamemap() -> GameMap {
-    let mut new_map = HashMap::new();
-
-    let mut range = rand::thread_rng();
-    let seed1 = range.gen_range(0, u32::MAX);
-    let seed2 = range.gen_range(0, u32::MAX);
-    let seed3 = range.gen_range(0, u32::MAX);
-    for x in -3..3 {
-        for z in -3..3 {
-            let (xx, zz) = ((x + 3) as usize, (z + 3) as usize);
-            let mut chunk = random_chunk(xx, zz, seed1, seed2, seed3);
-            let proper_y: f32 = CHUNK_HEIGHT as f32;
-            if x == 0 && z == 0 {
-                chunk
-                    .entities
-                    .push(Arc::new(Mutex::new(entities::EntityStatus {
-                        entity_type: "MainPlayer".to_string(),
-                        health: 20,
-                        position: Vec3::new(0., proper_y, 0.),
-                        rotation: PI * 0.0,
-                        scaling: Vec3::new(1., 1., 1.),
-                        velocity: Vec3::new(0., 0., 0.),
-                        attack_cd: 0.,
-                    })));
+fn upgrade_block_palette(
+    world_dir: &Path,
+    level_data: &mut LevelData,
+    block_registry: &blocks::BlockRegistry,
+) -> std::io::Result<HashMap<String, usize>> {
+    let mut unknown_blocks: HashMap<String, usize> = HashMap::new();
+    loop {
+        match &level_data.remapping {
+            Some(remapping) => println!(
+                "Resuming the remapping of saved chunks, {} regions already done",
+                remapping.done_regions.len()
+            ),
+            None => {
+                if !needs_remapping(&level_data.block_palette, block_registry) {
+                    // Numeric IDs are unchanged.
+                    return Ok(unknown_blocks);
+                }
+                println!("Block palette changed, remapping saved chunks");
+                level_data.remapping = Some(Remapping {
+                    from_palette: saved_block_palette(&level_data.block_palette),
+                    done_regions: Vec::new(),
+                });
+                level_data.block_palette = block_registry.palette();
+                write_level(world_dir, level_data)?;
             }
-            if rand::random::<f32>() < 0.5 {
-                chunk
-                    .entities
-                    .push(Arc::new(Mutex::new(entities::EntityStatus {
-                        entity_type: "Creeper".to_string(),
-                        health: 20,
-                        position: Vec3::new(
-                            (x * 16) as f32 + rand::random::<f32>() * 16.,
-                            proper_y,
-                            (z * 16) as f32 + rand::random::<f32>() * 16.,
-                        ),
-                        rotation: PI * 0.75,
-                        scaling: Vec3::new(1., 1., 1.),
-                        velocity: Vec3::new(0., 0., 0.),
-                        attack_cd: 0.,
-                    })));
-                chunk
-                    .entities
-                    .push(Arc::new(Mutex::new(entities::EntityStatus {
-                        entity_type: "Player".to_string(),
-                        health: 20,
-                        position: Vec3::new(
-                            (x * 16) as f32 + rand::random::<f32>() * 16.,
-                            proper_y,
-                            (z * 16) as f32 + rand::random::<f32>() * 16.,
-                        ),
-                        rotation: PI * 1.5,
-                        scaling: Vec3::new(1., 1., 1.),
-                        velocity: Vec3::new(0., 0., 0.),
-                        attack_cd: 0.,
-                    })));
-                chunk
-                    .entities
-                    .push(Arc::new(Mutex::new(entities::EntityStatus {
-                        entity_type: "HuTao".to_string(),
-                        health: 10000,
-                        position: Vec3::new(
-                            (x * 16) as f32 + rand::random::<f32>() * 16.,
-                            proper_y,
-                            (z * 16) as f32 + rand::random::<f32>() * 16.,
-                        ),
-                        rotation: PI * 0.25,
-                        scaling: Vec3::new(1., 1., 1.),
-                        velocity: Vec3::new(0., 0., 0.),
-                        attack_cd: 0.,
-                    })));
-                chunk
-                    .entities
-                    .push(Arc::new(Mutex::new(entities::EntityStatus {
-                        entity_type: "Chicken".to_string(),
-                        health: 20,
-                        position: Vec3::new(
-                            (x * 16) as f32 + rand::random::<f32>() * 16.,
-                            proper_y,
-                            (z * 16) as f32 + rand::random::<f32>() * 16.,
-                        ),
-                        rotation: PI * 0.25,
-                        scaling: Vec3::new(1., 1., 1.),
-                        velocity: Vec3::new(0., 0., 0.),
-                        attack_cd: 0.,
-                    })));
+        }
+        // Chunks are remapped to the palette saved when the remapping started.
+        // If the registry has changed again since then, they are remapped again in the next loop.
+        let remapping = level_data.remapping.clone().unwrap();
+        let new_ids = palette_mapping(&remapping.from_palette, &level_data.block_palette);
+        // Each region file is read and rewritten once.
+        for region in region::saved_regions(world_dir)? {
+            if remapping.done_regions.contains(&region) {
+                continue;
+            }
+            let mut region_file = region::RegionFile::open(&region::region_path(world_dir, region))?;
+            for index in region_file.chunk_indices() {
+                let chunk = region::decode_chunk(region_file.read_chunk(index).unwrap())?;
+                remap_block_ids(&chunk, &remapping.from_palette, &new_ids, &mut unknown_blocks);
+                region_file.write_chunk(index, region::encode_chunk(&chunk)?);
             }
-            new_map.insert((x, z), chunk);
+            region_file.save()?;
+            level_data.remapping.as_mut().unwrap().done_regions.push(region);
+            write_level(world_dir, level_data)?;
         }
+        level_data.remapping = None;
+        write_level(world_dir, level_data)?;
     }
-    return GameMap { map: new_map };
+}
+
+/// A remapping of the saved chunks of a world to a new block palette, which is in progress until all regions are done.
+#[derive(Serialize, Deserialize, Clone)]
+struct Remapping {
+    /// The block palette that chunks of regions not done yet are saved with.
+    from_palette: Vec<String>,
+    /// The regions whose chunks are already remapped to the palette of the world.
+    done_regions: Vec<(i32, i32)>,
+}
+
+/// World data other than chunks, stored in `level.json` of a world's save folder.
+#[derive(Serialize, Deserialize, Default)]
+struct LevelData {
+    /// The save format version. See the `migration` module.
+    format_version: u32,
+    #[serde(default)]
+    block_palette: Vec<String>,
+    /// The world seed. None in worlds created before world seeds existed.
+    #[serde(default)]
+    seed: Option<u64>,
+    #[serde(default)]
+    terrain_seeds: [u32; 3],
+    /// The world generator. Worlds created before generators were selectable use the noise generator.
+    #[serde(default)]
+    generator: worldgen::GeneratorSettings,
+    /// The height of the world in blocks.
+    #[serde(default = "legacy_world_height")]
+    height: usize,
+    /// The world time in seconds. Worlds created before the day cycle existed start at sunrise.
+    #[serde(default)]
+    time: f64,
+    /// The weather. Worlds created before weather existed start clear, and change weather at once.
+    #[serde(default)]
+    weather: weather::Weather,
+    /// Number of game ticks done in the world, which scheduled ticks are due at.
+    #[serde(default)]
+    tick: u64,
+    /// Number of random blocks of each chunk section that get a random tick in each game tick.
+    /// It can be changed by editing `level.json`.
+    #[serde(default = "default_random_tick_speed")]
+    random_tick_speed: u32,
+    /// The chunk that the main player is in. It is loaded first.
+    #[serde(default)]
+    player_chunk: (i32, i32),
+    /// A remapping of saved chunks to `block_palette` that is not finished. See `upgrade_block_palette`.
+    #[serde(default)]
+    remapping: Option<Remapping>,
+}
+
+/// Write the level data to `level.json` in the world's save folder.
+fn write_level(world_dir: &Path, level_data: &LevelData) -> std::io::Result<()> {
+    let serialized_level_data = serde_json::to_string(level_data)?;
+    region::write_atomically(&world_dir.join("level.json"), serialized_level_data.as_bytes())?;
+    return Ok(());
+}
+
+/// Save world data other than chunks to `level.json` in the world's save folder.
+pub fn save_level(gamemap: &GameMap, world_dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(world_dir)?;
+    let level_data = LevelData {
+        format_version: migration::SAVE_FORMAT_VERSION,
+        block_palette: gamemap.block_palette.clone(),
+        seed: Some(gamemap.seed),
+        terrain_seeds: gamemap.terrain_seeds,
+        generator: gamemap.generator.clone(),
+        height: gamemap.height,
+        time: gamemap.time,
+        weather: gamemap.weather,
+        tick: gamemap.tick,
+        random_tick_speed: gamemap.random_tick_speed,
+        player_chunk: gamemap.find_main_player_chunk().unwrap_or((0, 0)),
+        remapping: None,
+    };
+    return write_level(world_dir, &level_data);
+}
+
+/// Load world data other than chunks from `level.json` in the world's save folder.
+fn load_level(world_dir: &Path) -> Result<LevelData, WorldLoadError> {
+    let level_data: LevelData =
+        serde_json::from_str(&std::fs::read_to_string(world_dir.join("level.json"))?)?;
+    return Ok(level_data);
 }
 
 /**
-Load a game map from a file.
-Returns GameMap if the file is successfully loaded. Otherwise panics.
-(The Bevy framework does not support returning a Result here.)
+Load a game map from a world's save folder, with only the main player's chunk loaded.
+Other chunks are loaded by the `chunk_loading` module as the player approaches.
+A save in an older format (including an old single-JSON save) is upgraded first by the `migration` module,
+and saved chunks are remapped (after backing up the world) if the block registry has changed.
+A new world is created only if there is certainly no save, with `new_world_settings`.
+Returns the error if the save cannot be read or is malformed. The save is never replaced in that case.
 */
-pub fn load_gamemap(world_name: &str) -> GameMap {
-    let filename = format!("./saves/{}.json", world_name);
-    println!("Loading world from {}", filename);
-    match std::fs::read_to_string(filename) {
-        Ok(serialized_gamemap) => match serde_json::from_str(&serialized_gamemap) {
-            Ok(gamemap) => gamemap,
-            Err(err) => panic!("Failed to deserialize map: {}", err),
-        },
-        Err(err) => new_gamemap(),
+pub fn load_gamemap(
+    world_name: &str,
+    new_world_settings: &NewWorldSettings,
+    block_registry: &blocks::BlockRegistry,
+) -> Result<GameMap, WorldLoadError> {
+    let saves_dir = Path::new(region::SAVES_DIR);
+    let world_dir = region::world_dir(saves_dir, world_name);
+    migration::migrate_world(saves_dir, world_name, block_registry)?;
+    // `try_exists` fails instead of returning false if e.g. the folder cannot be accessed,
+    // so that an unreadable world is not mistaken for a missing one.
+    if !world_dir.try_exists()? {
+        let seed = new_world_settings.seed.unwrap_or_else(rand::random);
+        println!(
+            "Creating world {} with seed {}, generator {:?}, height {} and random tick speed {}",
+            world_name,
+            seed,
+            new_world_settings.generator,
+            new_world_settings.height,
+            new_world_settings.random_tick_speed
+        );
+        let gamemap = new_gamemap(
+            seed,
+            new_world_settings.generator.clone(),
+            new_world_settings.height,
+            new_world_settings.random_tick_speed,
+            block_registry,
+        );
+        // Save the level data now, because chunks are saved as soon as they are unloaded.
+        if let Err(err) = save_level(&gamemap, &world_dir) {
+            println!("Failed to save level data of the new world: {}", err);
+        }
+        return Ok(gamemap);
     }
+    println!("Loading world from {:?}", world_dir);
+    let mut level_data = load_level(&world_dir)?;
+    level_data.generator.check(block_registry).map_err(WorldLoadError::Malformed)?;
+    if needs_remapping(&level_data.block_palette, block_registry) {
+        let backup_dir = region::create_backup(saves_dir, world_name)?;
+        println!("Backed up world to {:?} before remapping blocks", backup_dir);
+    }
+    let unknown_blocks = upgrade_block_palette(&world_dir, &mut level_data, block_registry)?;
+    report_unknown_blocks(unknown_blocks);
+    let seed = match level_data.seed {
+        Some(seed) => seed,
+        // A world created before world seeds existed. Its terrain seeds are kept,
+        // and other things are derived from a seed made of them.
+        None => ((level_data.terrain_seeds[0] as u64) << 32) | level_data.terrain_seeds[1] as u64,
+    };
+    let mut gamemap = GameMap {
+        map: HashMap::new(),
+        block_palette: block_registry.palette(),
+        seed,
+        terrain_seeds: level_data.terrain_seeds,
+        generator: level_data.generator,
+        height: level_data.height,
+        time: level_data.time,
+        weather: level_data.weather,
+        tick: level_data.tick,
+        random_tick_speed: level_data.random_tick_speed.min(MAX_RANDOM_TICK_SPEED),
+    };
+    gamemap.load_chunk(level_data.player_chunk, &world_dir, block_registry)?;
+    save_level(&gamemap, &world_dir)?;
+    return Ok(gamemap);
 }
 
 /**
-Find whether there is a game map from a file.
-Returns true if the file is successfully loaded. Otherwise retur false.
+Find whether there is a saved game map of the world, in either the region or the old format.
 */
 pub fn find_gamemap(world_name: &str) -> bool {
-    let filename = format!("./saves/{}.json", world_name);
-    println!("Loading world from {}", filename);
-    match std::fs::read_to_string(filename) {
-        Ok(serialized_gamemap) => true,
-        Err(err) => false,
-    }
+    let saves_dir = Path::new(region::SAVES_DIR);
+    return region::world_dir(saves_dir, world_name).exists()
+        || region::legacy_save_path(saves_dir, world_name).exists();
 }
 /**
-Save a game map to a file.
+Save all loaded chunks of a game map to the world's save folder,
+together with the block palette of the block registry.
 Returns Ok(()) if the map is successfully saved. Otherwise returns the error.
 */
 pub fn save_gamemap(
-    gamemap: &GameMap,
+    gamemap: &mut GameMap,
     world_name: &Res<WorldName>,
+    block_registry: &blocks::BlockRegistry,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let filename = format!("./saves/{}.json", world_name.name.clone().unwrap());
-    match serde_json::to_string(&gamemap) {
-        Ok(serialized_gamemap) => match std::fs::write(&filename, &serialized_gamemap) {
-            Ok(()) => Ok(()),
-            Err(err) => Err(Box::new(err)),
-        },
-        Err(err) => Err(Box::new(err)),
-    }
+    let world_dir = region::world_dir(Path::new(region::SAVES_DIR), &world_name.name.clone().unwrap());
+    gamemap.block_palette = block_registry.palette();
+    region::save_chunks(&world_dir, gamemap.map.iter())?;
+    save_level(gamemap, &world_dir)?;
+    return Ok(());
 }
@@ -1,6 +1,8 @@
 use crate::*;
+use bevy::asset::LoadState;
 use bevy::ecs::system::EntityCommands;
 use bevy::prelude::*;
+use bevy::render::texture::ImageSampler;
 use std::collections::HashMap;
 use std::f32::consts::PI;
 use std::sync::{Arc, Mutex};
@@ -9,162 +11,207 @@ use std::sync::{Arc, Mutex};
 pub struct InitGamePlugin;
 impl Plugin for InitGamePlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<BlockEntityIDMap>();
+        app.init_resource::<GameAssets>();
         app.add_system(init_blocks_and_entities.in_schedule(OnExit(GameState::MainMenu)));
+        app.add_system(load_world.in_schedule(OnEnter(GameState::Loading)));
         app.add_system(loading_process.in_set(OnUpdate(GameState::Loading)));
     }
 }
 
+/// Meshes, materials and models prepared when entering the game.
+/// They are used to spawn blocks and entities, including those of newly generated chunks.
 #[derive(Resource, Default)]
-pub struct BlockEntityIDMap {
-    pub map: HashMap<(i32, i32, i32), Entity>,
+pub struct GameAssets {
+    /// Textures of block faces, by texture name. They are packed into the block texture atlas.
+    pub block_textures: HashMap<String, Handle<Image>>,
+    /// The area of each block texture in the atlas, by texture name, in UV coordinates (0 to 1).
+    pub block_texture_rects: HashMap<String, Rect>,
+    /// The material shared by all blocks, using the block texture atlas.
+    pub block_material: Handle<block_material::BlockMaterial>,
+    pub entity_models: HashMap<String, Handle<Scene>>,
 }
 
 /**
- Initialize the whole scene in the game, in other words, load all blocks and entities and the camera.
+ Prepare the game when leaving the main menu: choose the world name, and start loading textures and models.
+ The world itself is loaded in `load_world`.
 */
 fn init_blocks_and_entities(
-    mut commands: Commands,
     asset_server: Res<AssetServer>,
+    mut world_name: ResMut<gamemap::WorldName>,
+    mut game_assets: ResMut<GameAssets>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    if world_name.name.is_none() {
+        *world_name = gamemap::WorldName {
+            name: Some("New World".to_string()),
+        };
+    }
+    // Load textures of every kind of blocks. The atlas is built in `loading_process` when they are loaded.
+    game_assets.block_textures = load_block_textures(&asset_server, &block_registry);
+    game_assets.entity_models = load_entity_models(&asset_server);
+}
+
+/**
+ Load the game map (or create a new one), and spawn all its blocks and entities, the camera, the sun and the moon.
+ If the world fails to load, the error is kept in `WorldLoadFailure` and the game state is set to LoadFailed.
+ This runs every time the Loading state is entered, so loading can be retried.
+*/
+fn load_world(
+    mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<StandardMaterial>>,
     mut game_map: ResMut<gamemap::GameMap>,
-    mut world_name: ResMut<gamemap::WorldName>,
-    mut block_entity_id_map: ResMut<BlockEntityIDMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    mut light_map: ResMut<lighting::LightMap>,
+    mut world_clock: ResMut<daycycle::WorldClock>,
+    mut weather: ResMut<weather::Weather>,
+    mut load_failure: ResMut<gamemap::WorldLoadFailure>,
+    mut game_state: ResMut<NextState<GameState>>,
+    world_name: Res<gamemap::WorldName>,
+    new_world_settings: Res<gamemap::NewWorldSettings>,
+    game_assets: Res<GameAssets>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
-    // Load game map or create a new game map.
-    *game_map = match &world_name.name {
-        Some(name) => gamemap::load_gamemap(name),
-        None => {
-            *world_name = gamemap::WorldName {
-                name: Some("New World".to_string()),
-            };
-            gamemap::new_gamemap()
+    let world_name = world_name.name.as_ref().unwrap();
+    *game_map = match gamemap::load_gamemap(world_name, &new_world_settings, &block_registry) {
+        Ok(game_map) => game_map,
+        Err(err) => {
+            println!("Failed to load world {}: {}", world_name, err);
+            load_failure.error = Some(err);
+            game_state.set(GameState::LoadFailed);
+            return;
         }
     };
-    // Prepare model for a block.
-    let block_mesh = meshes.add(
-        shape::Box {
-            min_x: 0.,
-            max_x: 1.,
-            min_y: 0.,
-            max_y: 1.,
-            min_z: 0.,
-            max_z: 1.,
-        }
-        .into(),
-    );
-    // Prepare material for every kind of blocks.
-    let block_materials: Vec<Handle<StandardMaterial>> =
-        load_block_textures(&asset_server, materials);
-    let entity_models: HashMap<String, Handle<Scene>> = load_entity_models(&asset_server);
+    load_failure.error = None;
+    *chunk_meshes = chunk_mesh::ChunkMeshes::default();
+    *light_map = lighting::LightMap::default();
 
-    // Spawn all blocks in the gamemap.
-    for &(chunks_x, chunks_z) in game_map.map.keys() {
-        let chunk = &game_map.map[&(chunks_x, chunks_z)];
-        let chunk_blocks = chunk.blocks.lock().unwrap();
-        for x in 0..gamemap::CHUNK_SIZE {
-            for y in 0..gamemap::CHUNK_HEIGHT {
-                for z in 0..gamemap::CHUNK_SIZE {
-                    let block_id = chunk_blocks[x][y][z];
-                    if let Some(block_material) = block_materials.get(block_id as usize) {
-                        let block_entity_id = commands
-                            .spawn((
-                                blocks::Block,
-                                PbrBundle {
-                                    mesh: block_mesh.clone(),
-                                    material: block_material.clone(),
-                                    transform: Transform::from_xyz(
-                                        (chunks_x * gamemap::CHUNK_SIZE as i32 + x as i32) as f32,
-                                        y as f32,
-                                        (chunks_z * gamemap::CHUNK_SIZE as i32 + z as i32) as f32,
-                                    ),
-                                    ..default()
-                                },
-                            ))
-                            .id();
-                        block_entity_id_map.map.insert(
-                            (
-                                chunks_x * gamemap::CHUNK_SIZE as i32 + x as i32,
-                                y as i32,
-                                chunks_z * gamemap::CHUNK_SIZE as i32 + z as i32,
-                            ),
-                            block_entity_id,
-                        );
-                    } // If block_id is negative or out of bound, treat as air.
-                }
-            }
-        }
+    // Spawn all blocks and entities in the gamemap.
+    // Only chunks near the main player are loaded now. Others are loaded by the `chunk_loading` module.
+    for (&chunk_key, chunk) in game_map.map.iter() {
+        spawn_chunk(
+            &mut commands,
+            chunk_key,
+            chunk,
+            &game_assets,
+            &mut chunk_meshes,
+            &mut meshes,
+        );
     }
 
-    // Spawn all entities in the scene.
-    for &(chunks_x, chunks_z) in game_map.map.keys() {
-        let chunk = &game_map.map[&(chunks_x, chunks_z)];
-        for entity_status_locked in &chunk.entities {
-            let entity_status = entity_status_locked.lock().unwrap();
-            let mut entity_transform: Transform =
-                Transform::from_translation(entity_status.position)
-                    .with_scale(entity_status.scaling);
-            entity_transform.rotate_y(entity_status.rotation);
-            let entity_model_name = find_model_name_by_type(&entity_status.entity_type);
-            // First spawn the entity's status pointer and bounding box.
-            let mut entity_commands = commands.spawn((
-                entities::EntityStatusPointer {
-                    pointer: Arc::clone(entity_status_locked),
+    // Spawn the sun and the moon. They only light entities; blocks are lit by the `lighting` module.
+    *world_clock = daycycle::WorldClock {
+        time: game_map.time,
+    };
+    daycycle::spawn_sun_and_moon(&mut commands);
+    *weather = game_map.weather;
+}
+
+/// The loading process waits until all block textures are loaded,
+/// then builds the block texture atlas, and set game state to InGame.
+/// Does nothing if the world failed to load.
+fn loading_process(
+    mut game_state: ResMut<NextState<GameState>>,
+    asset_server: Res<AssetServer>,
+    mut game_assets: ResMut<GameAssets>,
+    mut images: ResMut<Assets<Image>>,
+    materials: ResMut<Assets<block_material::BlockMaterial>>,
+    load_failure: Res<gamemap::WorldLoadFailure>,
+) {
+    if load_failure.error.is_some() {
+        return;
+    }
+    println!("Loading...");
+    match asset_server.get_group_load_state(game_assets.block_textures.values().map(|handle| handle.id())) {
+        LoadState::Loaded => {}
+        LoadState::Failed => panic!("Failed to load block textures. Please eusure that block textures are in ./assets/blocks/ folder. "),
+        _ => return,
+    }
+    build_block_texture_atlas(&mut game_assets, &mut images, materials);
+    // Change game state from Loading to InGame.
+    game_state.set(GameState::InGame);
+}
+
+/**
+ Spawn all entities of a chunk, and mark the chunk's mesh to be built.
+*/
+pub fn spawn_chunk(
+    commands: &mut Commands,
+    chunk_key: (i32, i32),
+    chunk: &gamemap::Chunk,
+    game_assets: &GameAssets,
+    chunk_meshes: &mut chunk_mesh::ChunkMeshes,
+    meshes: &mut ResMut<Assets<Mesh>>,
+) {
+    // Blocks are rendered by the chunk's mesh.
+    chunk_meshes.mark_chunk_and_neighbors_dirty(chunk_key);
+
+    // Spawn all entities in the chunk.
+    for entity_status_locked in &chunk.entities {
+        spawn_entity(commands, entity_status_locked, game_assets, meshes);
+    }
+}
+
+/**
+ Spawn an entity with its collision box, tags and shown model.
+ The entity must be in a chunk's entities, or added to them.
+*/
+pub fn spawn_entity(
+    commands: &mut Commands,
+    entity_status_locked: &Arc<Mutex<entities::EntityStatus>>,
+    game_assets: &GameAssets,
+    meshes: &mut ResMut<Assets<Mesh>>,
+) {
+    let entity_status = entity_status_locked.lock().unwrap();
+    let mut entity_transform: Transform = Transform::from_translation(entity_status.position)
+        .with_scale(entity_status.scaling);
+    entity_transform.rotate_y(entity_status.rotation);
+    // First spawn the entity's status pointer and bounding box.
+    let mut entity_commands = commands.spawn((
+        entities::EntityStatusPointer {
+            pointer: Arc::clone(entity_status_locked),
+        },
+        PbrBundle {
+            mesh: get_collision_box_by_type(&entity_status.entity_type, meshes),
+            transform: entity_transform,
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+    ));
+    // Then insert entity tags into the entity.
+    insert_entity_tags(&mut entity_commands, &entity_status.entity_type);
+    // Then spawn the entity's shown model.
+    let model_transform = get_proper_model_transform_by_type(&entity_status.entity_type);
+    entity_commands.with_children(|parent| match &entity_status.block {
+        // Falling blocks and items look like their block. The mesh is built by the `falling_blocks` module.
+        Some(block) => {
+            parent.spawn((
+                falling_blocks::BlockModel {
+                    block: block.clone(),
                 },
-                PbrBundle {
-                    mesh: get_collision_box_by_type(&entity_status.entity_type, &mut meshes),
-                    transform: entity_transform,
-                    visibility: Visibility::Hidden,
+                SpatialBundle {
+                    transform: model_transform,
+                    visibility: Visibility::Visible,
                     ..default()
                 },
             ));
-            // Then insert entity tags into the entity.
-            insert_entity_tags(&mut entity_commands, &entity_status.entity_type);
-            // Then spawn the entity's shown model.
-            entity_commands.with_children(|parent| {
-                parent.spawn((SceneBundle {
-                    scene: entity_models
-                        .get(entity_model_name)
-                        .expect(&format!("Model not loaded: {}", entity_model_name))
-                        .clone(),
-                    transform: get_proper_model_transform_by_type(&entity_status.entity_type),
-                    visibility: match &entity_status.entity_type as &str {
-                        "MainPlayer" => Visibility::Hidden,
-                        _ => Visibility::Visible,
-                    },
-                    ..default()
-                },));
-            });
         }
-    }
-
-    // Spawn the sunlight.
-    let mut sunlight_direction = Transform::from_rotation(Quat::from_rotation_x(-PI * 0.25));
-    for _ in 0..4 {
-        commands.spawn(DirectionalLightBundle {
-            directional_light: DirectionalLight {
-                illuminance: 20000.,
-                shadows_enabled: false,
+        None => {
+            let entity_model_name = find_model_name_by_type(&entity_status.entity_type);
+            parent.spawn((SceneBundle {
+                scene: game_assets
+                    .entity_models
+                    .get(entity_model_name)
+                    .expect(&format!("Model not loaded: {}", entity_model_name))
+                    .clone(),
+                transform: model_transform,
+                visibility: match &entity_status.entity_type as &str {
+                    "MainPlayer" => Visibility::Hidden,
+                    _ => Visibility::Visible,
+                },
                 ..default()
-            },
-            transform: sunlight_direction,
-            ..default()
-        });
-        sunlight_direction.rotate_y(PI * 0.5);
-    }
-
-    // std::thread::sleep(std::time::Duration::from_secs_f32(2.0));
-}
-
-/// The loading process does nothing.
-/// It waits until all entities are spawn, and set game state to InGame.
-fn loading_process(mut game_state: ResMut<NextState<GameState>>) {
-    // Do nothing.
-    // Change game state from Loading to InGame.
-    println!("Loading...");
-    // std::thread::sleep(std::time::Duration::from_secs_f32(0.5));
-    game_state.set(GameState::InGame);
+            },));
+        }
+    });
 }
 
 /// A "tag" component for the game camera.
@@ -172,24 +219,59 @@ fn loading_process(mut game_state: ResMut<NextState<GameState>>) {
 pub struct GameCamera;
 
 /**
- Load textures of every kind of blocks.
+ Load textures of all faces of every kind of blocks in the block registry.
+ Returns textures by texture name.
 */
 fn load_block_textures(
     asset_server: &Res<AssetServer>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) -> Vec<Handle<StandardMaterial>> {
-    let block_textures = asset_server.load_folder("blocks")
-        .expect("Failed to load block texture. Please eusure that block texture is in ./assets/blocks/ folder. ");
-    // I think panic here is necessary. --XHZ
-    let mut block_materials: Vec<Handle<StandardMaterial>> = Vec::new();
-    for block_texture in block_textures {
-        block_materials.push(materials.add(StandardMaterial {
-            base_color_texture: Some(block_texture.typed()),
-            reflectance: 0.0,
-            ..default()
-        }));
+    block_registry: &blocks::BlockRegistry,
+) -> HashMap<String, Handle<Image>> {
+    let mut block_textures: HashMap<String, Handle<Image>> = HashMap::new();
+    for (_block_id, block) in block_registry.iter() {
+        for texture_name in [&block.textures.top, &block.textures.side, &block.textures.bottom] {
+            if !block_textures.contains_key(texture_name) {
+                let block_texture: Handle<Image> =
+                    asset_server.load(format!("blocks/{}.png", texture_name));
+                block_textures.insert(texture_name.clone(), block_texture);
+            }
+        }
+    }
+    return block_textures;
+}
+
+/**
+ Pack all loaded block textures into one texture atlas, and create the material shared by all blocks.
+ For animated textures (a vertical strip of frames), only the first frame is used.
+*/
+fn build_block_texture_atlas(
+    game_assets: &mut GameAssets,
+    images: &mut ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<block_material::BlockMaterial>>,
+) {
+    let mut atlas_builder = TextureAtlasBuilder::default();
+    for block_texture in game_assets.block_textures.values() {
+        atlas_builder.add_texture(block_texture.clone(), images.get(block_texture).unwrap());
     }
-    return block_materials;
+    let atlas = atlas_builder
+        .finish(images)
+        .expect("Block textures do not fit into the texture atlas. ");
+    game_assets.block_texture_rects.clear();
+    for (texture_name, block_texture) in game_assets.block_textures.iter() {
+        let rect = atlas.textures[atlas.get_texture_index(block_texture).unwrap()];
+        // Only the first frame (the top square) of animated textures.
+        let max = Vec2::new(rect.max.x, f32::min(rect.max.y, rect.min.y + rect.width()));
+        game_assets.block_texture_rects.insert(
+            texture_name.clone(),
+            Rect::from_corners(rect.min / atlas.size, max / atlas.size),
+        );
+    }
+    // Pixel art should not be blurred.
+    images.get_mut(&atlas.texture).unwrap().sampler_descriptor = ImageSampler::nearest();
+    // Sky darkening is set by `daycycle::update_sky` in the first frame in game.
+    game_assets.block_material = materials.add(block_material::BlockMaterial {
+        sky_darkening: 0.,
+        texture: atlas.texture,
+    });
 }
 
 /**
@@ -255,6 +337,23 @@ fn get_collision_box_by_type(entity_type: &str, meshes: &mut ResMut<Assets<Mesh>
                     .into(),
             ),
         );
+        collision_boxes_initialized.insert(
+            "FallingBlock",
+            meshes.add(
+                shape::Box::from_corners(Vec3::new(-0.5, 0., -0.5), Vec3::new(0.5, 1.0, 0.5))
+                    .into(),
+            ),
+        );
+        collision_boxes_initialized.insert(
+            "Item",
+            meshes.add(
+                shape::Box::from_corners(
+                    Vec3::new(-0.125, 0., -0.125),
+                    Vec3::new(0.125, 0.25, 0.125),
+                )
+                .into(),
+            ),
+        );
         *COLLISION_BOXES.lock().unwrap() = Some(collision_boxes_initialized);
     }
     return COLLISION_BOXES
@@ -301,6 +400,8 @@ fn get_proper_model_transform_by_type(entity_type: &str) -> Transform {
         "Chicken" => Transform::from_scale(Vec3::new(1.0, 1.0, 1.0))
             .with_translation(Vec3::new(0., 0.9, 0.))
             .with_rotation(Quat::from_rotation_y(PI)),
+        "FallingBlock" => Transform::IDENTITY,
+        "Item" => Transform::from_scale(Vec3::new(0.25, 0.25, 0.25)),
         _ => panic!("Unknown entity type: {}", entity_type),
     }
 }
@@ -325,6 +426,8 @@ fn insert_entity_tags(entity_commands: &mut EntityCommands, entity_type: &str) {
         "Torch" => entity_commands.insert((entities::Entity, entities::Torch)),
         "HuTao" => entity_commands.insert((entities::Entity, entities::HuTao)),
         "Chicken" => entity_commands.insert((entities::Entity, entities::Chicken)),
+        "FallingBlock" => entity_commands.insert((entities::Entity, entities::FallingBlock)),
+        "Item" => entity_commands.insert((entities::Entity, entities::Item)),
         _ => panic!("Unknown entity type: {}", entity_type),
     };
 }
@@ -53,6 +53,7 @@ fn player_find_target(
     >,
     gamemap: Res<gamemap::GameMap>,
     meshes: Res<Assets<Mesh>>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
     // Clear targets.
     target.entity_status_ptr = None;
@@ -92,8 +93,11 @@ fn player_find_target(
             }
         }
         // Check collision with blocks.
-        let block_id = gamemap.query_block_by_xyz(points[point_id]).unwrap_or(-1);
-        if block_id > 0 {
+        let block_id = gamemap
+            .query_block_by_xyz(points[point_id])
+            .unwrap_or(blocks::AIR);
+        // Fluids cannot be targeted: the player reaches through them.
+        if block_registry.get(block_id).is_some() && block_registry.fluid(block_id).is_none() {
             target.block = Some(points[point_id]);
             break;
         }
@@ -144,30 +148,32 @@ pub fn send_event_to_block(
 fn handle_block_events(
     mut event_reader: EventReader<GameBlockEvent>,
     gamemap: ResMut<gamemap::GameMap>,
-    mut commands: Commands,
-    block_entity_id_map: ResMut<init_game::BlockEntityIDMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    mut light_map: ResMut<lighting::LightMap>,
+    mut block_updates: ResMut<block_updates::BlockUpdates>,
+    block_registry: Res<blocks::BlockRegistry>,
 ) {
+    let mut world = block_updates::BlockWorld {
+        game_map: &gamemap,
+        block_registry: &block_registry,
+        light_map: &mut light_map,
+        chunk_meshes: &mut chunk_meshes,
+        block_updates: &mut block_updates,
+    };
     for event in event_reader.iter() {
         let target_potision = event.target_position;
-        let chunk_key = gamemap.query_chunk_by_xyz(target_potision);
         match gamemap.to_integer(target_potision) {
-            Some((x, y, z)) => {
+            Some(_) => {
                 // Target block is found.
                 match event.operation {
                     GameEventOpration::HIT(_damage) => {
-                        gamemap.map.get(&chunk_key).unwrap().blocks.lock().unwrap()[x][y][z] = -1;
-                        commands
-                            .entity(
-                                *block_entity_id_map
-                                    .map
-                                    .get(&(
-                                        target_potision.x.floor() as i32,
-                                        target_potision.y.floor() as i32,
-                                        target_potision.z.floor() as i32,
-                                    ))
-                                    .unwrap(),
-                            )
-                            .despawn_recursive();
+                        let block_position = (
+                            target_potision.x.floor() as i32,
+                            target_potision.y.floor() as i32,
+                            target_potision.z.floor() as i32,
+                        );
+                        // Blocks next to the block get block updates, e.g. fluids may flow into it.
+                        world.set(block_position, blocks::AIR);
                     }
                     GameEventOpration::USE => {}
                 }
@@ -0,0 +1,466 @@
+//! Lighting
+//! ---
+//! Every block has two light levels from 0 to `MAX_LIGHT`:
+//! - Sky light comes from above the world. It goes straight down through transparent blocks without fading,
+//!   and fades by one level for every other step.
+//! - Block light comes from emissive blocks (see `BlockProperties::light_emission`) and torches,
+//!   and fades by one level for every step.
+//!
+//! Light spreads through transparent blocks only (a flood fill), across loaded chunks.
+//! It is computed when a chunk is loaded, and updated incrementally when a block changes
+//! (`LightMap::update_block`): the light that came through the block is removed, then light spreads back in.
+//! Light is not saved; it is computed again when chunks are loaded.
+//!
+//! Chunk meshes are shaded by the light in front of each face (see the `chunk_mesh` module).
+
+use crate::*;
+use bevy::prelude::*;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::MutexGuard;
+
+/// Plugin responsible for lighting loaded chunks, and keeping the light of torches up to date.
+pub struct LightingPlugin;
+impl Plugin for LightingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LightMap>();
+        app.add_systems(
+            (update_torch_lights, light_loaded_chunks)
+                .chain()
+                .before(chunk_mesh::rebuild_dirty_chunk_meshes)
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+    }
+}
+
+/// The highest light level.
+pub const MAX_LIGHT: u8 = 15;
+/// The light level of a torch.
+const TORCH_LIGHT: u8 = 14;
+/// At most this many loaded chunks are lit each frame, to avoid stuttering. The rest are lit in the next frames.
+const CHUNKS_LIT_PER_FRAME: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LightKind {
+    Sky,
+    Block,
+}
+
+/// Offsets to the six neighbors of a block. Straight down is the second one.
+const NEIGHBORS: [(i32, i32, i32); 6] = [
+    (0, 1, 0),
+    (0, -1, 0),
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+];
+const DOWN: (i32, i32, i32) = (0, -1, 0);
+
+/// Light levels of a chunk, indexed in x, y, z order like blocks.
+/// Sky light is in the high 4 bits and block light in the low 4 bits.
+struct ChunkLight {
+    height: usize,
+    levels: Vec<u8>,
+}
+
+impl ChunkLight {
+    fn new(height: usize) -> ChunkLight {
+        return ChunkLight {
+            height,
+            levels: vec![0; gamemap::CHUNK_SIZE * height * gamemap::CHUNK_SIZE],
+        };
+    }
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        return (x * self.height + y) * gamemap::CHUNK_SIZE + z;
+    }
+    fn get(&self, x: usize, y: usize, z: usize, kind: LightKind) -> u8 {
+        let level = self.levels[self.index(x, y, z)];
+        return match kind {
+            LightKind::Sky => level >> 4,
+            LightKind::Block => level & 0xf,
+        };
+    }
+    fn set(&mut self, x: usize, y: usize, z: usize, kind: LightKind, value: u8) {
+        let index = self.index(x, y, z);
+        let level = &mut self.levels[index];
+        *level = match kind {
+            LightKind::Sky => (*level & 0xf) | (value << 4),
+            LightKind::Block => (*level & 0xf0) | value,
+        };
+    }
+}
+
+/**
+The light levels of all lit chunks. This is a global resource.
+
+Chunks are lit by `light_loaded_chunks` after they are loaded.
+Whenever a block changes, call `update_block`, and rebuild the meshes of the chunks it returns.
+*/
+#[derive(Resource, Default)]
+pub struct LightMap {
+    chunks: HashMap<(i32, i32), ChunkLight>,
+    /// Light sources other than blocks (i.e. torches), by block position.
+    entity_sources: HashMap<(i32, i32, i32), u8>,
+}
+
+impl LightMap {
+    /**
+    The sky light and block light at a block position.
+    Above the world there is full sky light, and below it there is no light.
+    Unlit chunks have full sky light.
+    */
+    pub fn light_at(&self, (x, y, z): (i32, i32, i32)) -> (u8, u8) {
+        let size = gamemap::CHUNK_SIZE as i32;
+        let chunk_light = match self.chunks.get(&(x.div_euclid(size), z.div_euclid(size))) {
+            Some(chunk_light) => chunk_light,
+            None => return (MAX_LIGHT, 0),
+        };
+        if y < 0 {
+            return (0, 0);
+        }
+        if y as usize >= chunk_light.height {
+            return (MAX_LIGHT, 0);
+        }
+        let (local_x, local_z) = (x.rem_euclid(size) as usize, z.rem_euclid(size) as usize);
+        return (
+            chunk_light.get(local_x, y as usize, local_z, LightKind::Sky),
+            chunk_light.get(local_x, y as usize, local_z, LightKind::Block),
+        );
+    }
+
+    /// Whether the chunk has been lit.
+    pub fn is_lit(&self, chunk_key: (i32, i32)) -> bool {
+        return self.chunks.contains_key(&chunk_key);
+    }
+
+    /**
+    Light a loaded chunk, spreading its light into loaded neighbor chunks and their light into it.
+    Returns the chunks whose light changed, including this one.
+    */
+    pub fn light_chunk(
+        &mut self,
+        game_map: &gamemap::GameMap,
+        chunk_key: (i32, i32),
+        block_registry: &blocks::BlockRegistry,
+    ) -> HashSet<(i32, i32)> {
+        let size = gamemap::CHUNK_SIZE as i32;
+        self.chunks
+            .insert(chunk_key, ChunkLight::new(game_map.height));
+        let mut lighter = Lighter::new(self, game_map, block_registry);
+        lighter.changed.insert(chunk_key);
+        let (origin_x, origin_z) = (chunk_key.0 * size, chunk_key.1 * size);
+        for kind in [LightKind::Sky, LightKind::Block] {
+            let mut queue = VecDeque::new();
+            for x in origin_x..origin_x + size {
+                for z in origin_z..origin_z + size {
+                    for y in 0..game_map.height as i32 {
+                        let source = lighter.source((x, y, z), kind);
+                        if source > 0 {
+                            lighter.set_light((x, y, z), kind, source);
+                            queue.push_back((x, y, z));
+                        }
+                    }
+                }
+            }
+            // Light of neighbor chunks spreads in from their borders.
+            for i in -1..=size {
+                for (x, z) in [
+                    (origin_x - 1, origin_z + i),
+                    (origin_x + size, origin_z + i),
+                    (origin_x + i, origin_z - 1),
+                    (origin_x + i, origin_z + size),
+                ] {
+                    for y in 0..game_map.height as i32 {
+                        if lighter.light((x, y, z), kind).unwrap_or(0) > 1 {
+                            queue.push_back((x, y, z));
+                        }
+                    }
+                }
+            }
+            lighter.spread(queue, kind);
+        }
+        return lighter.changed;
+    }
+
+    /// Forget the light of an unloaded chunk.
+    pub fn unlight_chunk(&mut self, chunk_key: (i32, i32)) {
+        self.chunks.remove(&chunk_key);
+    }
+
+    /**
+    Update light after the block at a position has changed (or a light source other than blocks was added or removed there).
+    Returns the chunks whose light changed.
+    */
+    pub fn update_block(
+        &mut self,
+        game_map: &gamemap::GameMap,
+        position: (i32, i32, i32),
+        block_registry: &blocks::BlockRegistry,
+    ) -> HashSet<(i32, i32)> {
+        let mut lighter = Lighter::new(self, game_map, block_registry);
+        for kind in [LightKind::Sky, LightKind::Block] {
+            let old_level = match lighter.light(position, kind) {
+                Some(old_level) => old_level,
+                None => continue, // Not in a lit chunk.
+            };
+            let mut queue = lighter.remove(position, old_level, kind);
+            let source = lighter.source(position, kind);
+            if source > lighter.light(position, kind).unwrap() {
+                lighter.set_light(position, kind, source);
+            }
+            queue.push_back(position);
+            for offset in NEIGHBORS {
+                queue.push_back(add(position, offset));
+            }
+            lighter.spread(queue, kind);
+        }
+        return lighter.changed;
+    }
+}
+
+fn add((x, y, z): (i32, i32, i32), (dx, dy, dz): (i32, i32, i32)) -> (i32, i32, i32) {
+    return (x + dx, y + dy, z + dz);
+}
+
+/// Blocks and light of loaded chunks during a light update, with the chunks whose light has changed.
+struct Lighter<'a> {
+    light_map: &'a mut LightMap,
+    game_map: &'a gamemap::GameMap,
+    /// The blocks of the chunks the update has reached, each locked once, when it is first read.
+    chunk_blocks: RefCell<HashMap<(i32, i32), MutexGuard<'a, sections::ChunkBlocks>>>,
+    block_registry: &'a blocks::BlockRegistry,
+    changed: HashSet<(i32, i32)>,
+}
+
+impl<'a> Lighter<'a> {
+    fn new(
+        light_map: &'a mut LightMap,
+        game_map: &'a gamemap::GameMap,
+        block_registry: &'a blocks::BlockRegistry,
+    ) -> Lighter<'a> {
+        return Lighter {
+            light_map,
+            game_map,
+            chunk_blocks: RefCell::new(HashMap::new()),
+            block_registry,
+            changed: HashSet::new(),
+        };
+    }
+
+    /// The chunk and the local coordinates of a position, if it is in the world.
+    fn locate(&self, (x, y, z): (i32, i32, i32)) -> Option<((i32, i32), (usize, usize, usize))> {
+        let size = gamemap::CHUNK_SIZE as i32;
+        let chunk_key = (x.div_euclid(size), z.div_euclid(size));
+        let chunk_light = self.light_map.chunks.get(&chunk_key)?;
+        if y < 0 || y as usize >= chunk_light.height {
+            return None;
+        }
+        let local = (
+            x.rem_euclid(size) as usize,
+            y as usize,
+            z.rem_euclid(size) as usize,
+        );
+        return Some((chunk_key, local));
+    }
+
+    /// The block at a position, or None if it is not in a loaded chunk.
+    fn block(&self, position: (i32, i32, i32)) -> Option<i32> {
+        let (chunk_key, (x, y, z)) = self.locate(position)?;
+        let mut chunk_blocks = self.chunk_blocks.borrow_mut();
+        let blocks = match chunk_blocks.entry(chunk_key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(self.game_map.map.get(&chunk_key)?.blocks.lock().unwrap())
+            }
+        };
+        return Some(blocks.get(x, y, z));
+    }
+
+    /// The light at a position, or None if it is not in a lit chunk.
+    fn light(&self, position: (i32, i32, i32), kind: LightKind) -> Option<u8> {
+        let (chunk_key, (x, y, z)) = self.locate(position)?;
+        return Some(self.light_map.chunks[&chunk_key].get(x, y, z, kind));
+    }
+
+    fn set_light(&mut self, position: (i32, i32, i32), kind: LightKind, value: u8) {
+        if let Some((chunk_key, (x, y, z))) = self.locate(position) {
+            let chunk_light = self.light_map.chunks.get_mut(&chunk_key).unwrap();
+            if chunk_light.get(x, y, z, kind) != value {
+                chunk_light.set(x, y, z, kind, value);
+                self.changed.insert(chunk_key);
+            }
+        }
+    }
+
+    fn is_transparent(&self, position: (i32, i32, i32)) -> bool {
+        return match self.block(position) {
+            Some(block_id) => self.block_registry.is_transparent(block_id),
+            None => false,
+        };
+    }
+
+    /// The light a position gives off by itself.
+    fn source(&self, position: (i32, i32, i32), kind: LightKind) -> u8 {
+        let block_id = match self.block(position) {
+            Some(block_id) => block_id,
+            None => return 0,
+        };
+        return match kind {
+            // The top of the world is lit by the sky.
+            LightKind::Sky => {
+                let above = add(position, (0, 1, 0));
+                if self.block_registry.is_transparent(block_id) && self.locate(above).is_none() {
+                    MAX_LIGHT
+                } else {
+                    0
+                }
+            }
+            LightKind::Block => {
+                let entity_source = self.light_map.entity_sources.get(&position).copied();
+                self.block_registry
+                    .light_emission(block_id)
+                    .max(entity_source.unwrap_or(0))
+            }
+        };
+    }
+
+    /// Spread light from the positions in the queue to their neighbors, and so on.
+    fn spread(&mut self, mut queue: VecDeque<(i32, i32, i32)>, kind: LightKind) {
+        while let Some(position) = queue.pop_front() {
+            let level = match self.light(position, kind) {
+                Some(level) if level > 0 => level,
+                _ => continue,
+            };
+            for offset in NEIGHBORS {
+                let neighbor = add(position, offset);
+                if !self.is_transparent(neighbor) {
+                    continue;
+                }
+                let new_level = if kind == LightKind::Sky && offset == DOWN && level == MAX_LIGHT {
+                    MAX_LIGHT
+                } else {
+                    level - 1
+                };
+                if self.light(neighbor, kind).unwrap() < new_level {
+                    self.set_light(neighbor, kind, new_level);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    /**
+    Remove the light that came from a position which had `old_level`, setting it to 0.
+    Light sources in the darkened area are lit again.
+    Returns the positions where light should spread from, to fill the darkened area again.
+    */
+    fn remove(
+        &mut self,
+        position: (i32, i32, i32),
+        old_level: u8,
+        kind: LightKind,
+    ) -> VecDeque<(i32, i32, i32)> {
+        let mut spread_queue = VecDeque::new();
+        let mut remove_queue = VecDeque::from([(position, old_level)]);
+        let mut darkened = vec![position];
+        self.set_light(position, kind, 0);
+        while let Some((position, level)) = remove_queue.pop_front() {
+            for offset in NEIGHBORS {
+                let neighbor = add(position, offset);
+                let neighbor_level = match self.light(neighbor, kind) {
+                    Some(neighbor_level) if neighbor_level > 0 => neighbor_level,
+                    _ => continue,
+                };
+                let lit_from_here = neighbor_level < level
+                    || (kind == LightKind::Sky && offset == DOWN && level == MAX_LIGHT);
+                if lit_from_here {
+                    self.set_light(neighbor, kind, 0);
+                    remove_queue.push_back((neighbor, neighbor_level));
+                    darkened.push(neighbor);
+                } else {
+                    spread_queue.push_back(neighbor);
+                }
+            }
+        }
+        for position in darkened {
+            let source = self.source(position, kind);
+            if source > 0 {
+                self.set_light(position, kind, source);
+                spread_queue.push_back(position);
+            }
+        }
+        return spread_queue;
+    }
+}
+
+/**
+Light chunks that have been loaded, up to `CHUNKS_LIT_PER_FRAME` of them, and forget the light of chunks that have been unloaded.
+Meshes of chunks whose light changed are rebuilt.
+*/
+fn light_loaded_chunks(
+    mut light_map: ResMut<LightMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    game_map: Res<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    let unloaded_chunks: Vec<(i32, i32)> = light_map
+        .chunks
+        .keys()
+        .copied()
+        .filter(|chunk_key| !game_map.map.contains_key(chunk_key))
+        .collect();
+    for chunk_key in unloaded_chunks {
+        light_map.unlight_chunk(chunk_key);
+    }
+    let unlit_chunks: Vec<(i32, i32)> = game_map
+        .map
+        .keys()
+        .copied()
+        .filter(|&chunk_key| !light_map.is_lit(chunk_key))
+        .take(CHUNKS_LIT_PER_FRAME)
+        .collect();
+    for chunk_key in unlit_chunks {
+        for changed_chunk in light_map.light_chunk(&game_map, chunk_key, &block_registry) {
+            chunk_meshes.mark_chunk_dirty(changed_chunk);
+        }
+    }
+}
+
+/// Make torches in loaded chunks light sources, and stop torches that are gone from giving light.
+fn update_torch_lights(
+    mut light_map: ResMut<LightMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    game_map: Res<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    let mut torches: HashMap<(i32, i32, i32), u8> = HashMap::new();
+    for chunk in game_map.map.values() {
+        for entity in chunk.entities.iter() {
+            let entity = entity.lock().unwrap();
+            if entity.entity_type == "Torch" {
+                let position = entity.position.floor();
+                torches.insert(
+                    (position.x as i32, position.y as i32, position.z as i32),
+                    TORCH_LIGHT,
+                );
+            }
+        }
+    }
+    if torches == light_map.entity_sources {
+        return;
+    }
+    let changed_positions: HashSet<(i32, i32, i32)> = torches
+        .keys()
+        .chain(light_map.entity_sources.keys())
+        .copied()
+        .filter(|position| torches.get(position) != light_map.entity_sources.get(position))
+        .collect();
+    light_map.entity_sources = torches;
+    for position in changed_positions {
+        for changed_chunk in light_map.update_block(&game_map, position, &block_registry) {
+            chunk_meshes.mark_chunk_dirty(changed_chunk);
+        }
+    }
+}
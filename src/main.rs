@@ -1,11 +1,29 @@
+mod autosave;
+mod biomes;
+mod block_material;
+mod block_updates;
 mod blocks;
+mod chunk_loading;
+mod chunk_mesh;
 mod control;
+mod daycycle;
+mod decoration;
 mod entities;
+mod falling_blocks;
+mod fluids;
 mod gamemap;
 mod init_game;
 mod interaction;
+mod lighting;
+mod migration;
+mod ores;
 mod player;
+mod region;
+mod sections;
 mod ui;
+mod vegetation;
+mod weather;
+mod worldgen;
 
 use bevy::app::PluginGroupBuilder;
 use bevy::prelude::*;
@@ -20,17 +38,28 @@ pub enum GameState {
     MainMenu,
     Pause,
     Loading,
+    /// The world failed to load. The player can retry, open a backup, or go back to the main menu.
+    LoadFailed,
 }
 
 /**
 The plugin group that is used in game,
-including render, control, collision, and entity update.
+including render, chunk loading, autosave, control, collision, and entity update.
  */
 struct InGamePluginGroup;
 impl PluginGroup for InGamePluginGroup {
     fn build(self) -> PluginGroupBuilder {
         let builder = PluginGroupBuilder::start::<Self>();
         let builder = builder.add(init_game::InitGamePlugin);
+        let builder = builder.add(chunk_loading::ChunkLoadingPlugin);
+        let builder = builder.add(autosave::AutosavePlugin);
+        let builder = builder.add(block_material::BlockMaterialPlugin);
+        let builder = builder.add(chunk_mesh::ChunkMeshPlugin);
+        let builder = builder.add(lighting::LightingPlugin);
+        let builder = builder.add(daycycle::DayCyclePlugin);
+        let builder = builder.add(weather::WeatherPlugin);
+        let builder = builder.add(block_updates::BlockUpdatePlugin);
+        let builder = builder.add(falling_blocks::FallingBlockPlugin);
         let builder = builder.add(control::ControlPlugin);
         let builder = builder.add(entities::EntityUpdatePlugin);
         let builder = builder.add(interaction::InteractionPlugin);
@@ -38,11 +67,90 @@ impl PluginGroup for InGamePluginGroup {
     }
 }
 
+/**
+Parse command line arguments: `--seed <number or text>` sets the world seed of new worlds,
+`--generator <generator>` their world generator (see `worldgen::GeneratorSettings::parse`),
+`--height <blocks>` their height, and `--random-tick-speed <number>` their random tick speed.
+`--autosave-interval <seconds>` sets how often worlds are autosaved, and `--max-backups <number>`
+how many backups of each world are kept (see `autosave::AutosaveSettings`).
+*/
+fn parse_args(
+    block_registry: &blocks::BlockRegistry,
+) -> (gamemap::NewWorldSettings, autosave::AutosaveSettings) {
+    let mut new_world_settings = gamemap::NewWorldSettings::default();
+    let mut autosave_settings = autosave::AutosaveSettings::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => match args.next() {
+                Some(seed) => new_world_settings.seed = Some(gamemap::seed_from_text(&seed)),
+                None => println!("Missing value after --seed"),
+            },
+            "--generator" => match args.next() {
+                Some(generator) => {
+                    match worldgen::GeneratorSettings::parse(&generator, block_registry) {
+                        Ok(generator) => new_world_settings.generator = generator,
+                        Err(err) => println!("Invalid world generator: {}", err),
+                    }
+                }
+                None => println!("Missing value after --generator"),
+            },
+            "--height" => match args.next().map(|height| height.parse::<usize>()) {
+                Some(Ok(height))
+                    if height > 0
+                        && height % sections::SECTION_HEIGHT == 0
+                        && height <= gamemap::MAX_WORLD_HEIGHT =>
+                {
+                    new_world_settings.height = height
+                }
+                Some(_) => println!(
+                    "The height must be a multiple of {} up to {}",
+                    sections::SECTION_HEIGHT,
+                    gamemap::MAX_WORLD_HEIGHT
+                ),
+                None => println!("Missing value after --height"),
+            },
+            "--random-tick-speed" => match args.next().map(|speed| speed.parse::<u32>()) {
+                Some(Ok(random_tick_speed))
+                    if random_tick_speed <= gamemap::MAX_RANDOM_TICK_SPEED =>
+                {
+                    new_world_settings.random_tick_speed = random_tick_speed
+                }
+                Some(_) => println!(
+                    "The random tick speed must be a number up to {}",
+                    gamemap::MAX_RANDOM_TICK_SPEED
+                ),
+                None => println!("Missing value after --random-tick-speed"),
+            },
+            "--autosave-interval" => match args.next().map(|interval| interval.parse::<f32>()) {
+                Some(Ok(interval)) if interval > 0. => autosave_settings.interval = interval,
+                Some(_) => println!("The autosave interval must be a positive number of seconds"),
+                None => println!("Missing value after --autosave-interval"),
+            },
+            "--max-backups" => match args.next().map(|max_backups| max_backups.parse::<usize>()) {
+                Some(Ok(max_backups)) if max_backups > 0 => {
+                    autosave_settings.max_backups = max_backups
+                }
+                Some(_) => println!("The number of backups must be a positive number"),
+                None => println!("Missing value after --max-backups"),
+            },
+            _ => println!("Unknown argument {}", arg),
+        }
+    }
+    return (new_world_settings, autosave_settings);
+}
+
 fn main() {
     let mut app = App::new();
     app.add_state::<GameState>();
     app.init_resource::<gamemap::GameMap>();
     app.init_resource::<gamemap::WorldName>();
+    app.init_resource::<gamemap::WorldLoadFailure>();
+    let block_registry = blocks::BlockRegistry::default();
+    let (new_world_settings, autosave_settings) = parse_args(&block_registry);
+    app.insert_resource(new_world_settings);
+    app.insert_resource(autosave_settings);
+    app.insert_resource(block_registry);
     app.add_plugins(DefaultPlugins.set(WindowPlugin {
         primary_window: Some(Window {
             title: "RustMC!".to_string(),
@@ -0,0 +1,531 @@
+//! Save format versions and migration
+//! ---
+//! Every world's `level.json` records the save format version it was written in,
+//! and every chunk blob in the region files starts with it too (see the `region` module).
+//! When a world saved in an older version is loaded, it is upgraded step by step,
+//! one version at a time, by the migrations in `MIGRATIONS`. The world is backed up before migrating.
+//!
+//! Historical formats:
+//! - Version 0: a single `./saves/<world name>.json` with the whole serialized `GameMap`
+//!   (with or without a block palette). It is converted straight to the current version
+//!   by `region::convert_legacy_save`, which writes with the current encoders.
+//! - Version 1: the region format, without a version in `level.json` nor in chunk blobs.
+//! - Version 2: `format_version` in `level.json`, and a version before the blocks of every chunk blob.
+//! - Version 3: blocks of chunk blobs in sections, so that worlds can be taller.
+//!   Worlds without a height in `level.json` are `gamemap::LEGACY_WORLD_HEIGHT` high.
+//! - Version 4: sections of chunk blobs compressed with palettes (see `write_section_v4`).
+//! - Version 5: scheduled ticks after the entities of chunk blobs (see `region::write_scheduled_ticks`).
+//!
+//! When changing the save format (e.g. adding a field to `EntityStatus` without a serde default),
+//! bump `SAVE_FORMAT_VERSION` and append a migration from the previous version.
+
+use crate::*;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// The save format version written by this version of the game.
+pub const SAVE_FORMAT_VERSION: u32 = 5;
+
+/// A migration upgrades a world's save folder by one version.
+type Migration = fn(&Path) -> Result<(), gamemap::WorldLoadError>;
+
+/// `MIGRATIONS[i]` upgrades a world from version `i + 1` to version `i + 2`.
+/// Version 0 is not here, because old single-JSON saves are converted to the current version directly.
+const MIGRATIONS: [Migration; (SAVE_FORMAT_VERSION - 1) as usize] = [
+    migrate_1_to_2,
+    migrate_2_to_3,
+    migrate_3_to_4,
+    migrate_4_to_5,
+];
+
+/// Number of bytes of the blocks of a chunk blob in versions 1 and 2: little-endian `i32`s of a legacy-height chunk.
+const LEGACY_BLOCKS_LEN: usize =
+    gamemap::CHUNK_SIZE * gamemap::LEGACY_WORLD_HEIGHT * gamemap::CHUNK_SIZE * 4;
+
+/// Read the save format version of a world's save folder. Saves without a version are version 1.
+fn read_format_version(world_dir: &Path) -> Result<u32, gamemap::WorldLoadError> {
+    let level: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(world_dir.join("level.json"))?)?;
+    return match level.get("format_version") {
+        None => Ok(1),
+        Some(version) => match version.as_u64() {
+            Some(version) => Ok(version as u32),
+            None => Err(gamemap::WorldLoadError::Malformed(format!(
+                "invalid format version {}",
+                version
+            ))),
+        },
+    };
+}
+
+/// Set the save format version in a world's `level.json`, keeping everything else.
+fn write_format_version(world_dir: &Path, version: u32) -> Result<(), gamemap::WorldLoadError> {
+    let path = world_dir.join("level.json");
+    let mut level: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    match level.as_object_mut() {
+        Some(level) => level.insert("format_version".to_string(), version.into()),
+        None => {
+            return Err(gamemap::WorldLoadError::Malformed(
+                "level.json is not an object".to_string(),
+            ))
+        }
+    };
+    region::write_atomically(&path, serde_json::to_string(&level)?.as_bytes())?;
+    return Ok(());
+}
+
+/**
+Upgrade a world's save to the current save format version, converting an old single-JSON save if needed.
+Does nothing if the world is already in the current version, or has never been saved.
+Returns an error if the world was saved by a newer version of the game.
+*/
+pub fn migrate_world(
+    saves_dir: &Path,
+    world_name: &str,
+    block_registry: &blocks::BlockRegistry,
+) -> Result<(), gamemap::WorldLoadError> {
+    let world_dir = region::world_dir(saves_dir, world_name);
+    if !world_dir.try_exists()? {
+        if region::legacy_save_path(saves_dir, world_name).try_exists()? {
+            // The old save is kept as `<world name>.json.old`.
+            region::convert_legacy_save(saves_dir, world_name, block_registry)?;
+        }
+        return Ok(());
+    }
+    let mut version = read_format_version(&world_dir)?;
+    if version == 0 {
+        // Version 0 is never written in `level.json`.
+        return Err(gamemap::WorldLoadError::Malformed(
+            "invalid format version 0".to_string(),
+        ));
+    }
+    if version > SAVE_FORMAT_VERSION {
+        return Err(gamemap::WorldLoadError::Malformed(format!(
+            "saved in format version {} by a newer version of the game (this version supports up to {})",
+            version, SAVE_FORMAT_VERSION
+        )));
+    }
+    if version == SAVE_FORMAT_VERSION {
+        return Ok(());
+    }
+    let backup_dir = region::create_backup(saves_dir, world_name)?;
+    println!("Backed up world to {:?} before upgrading its save format", backup_dir);
+    while version < SAVE_FORMAT_VERSION {
+        println!("Upgrading save format from version {} to {}", version, version + 1);
+        MIGRATIONS[(version - 1) as usize](&world_dir)?;
+        version += 1;
+        write_format_version(&world_dir, version)?;
+    }
+    return Ok(());
+}
+
+/**
+Whether a decompressed chunk blob is in version 2: the version, the blocks, then the length of the entities
+and the entities. Blobs in version 1 have no version, but they are never mistaken for version 2,
+because the length of their entities would be read from the entities' JSON.
+A migration that is interrupted leaves the world in the old version with some chunks upgraded,
+which are skipped when it runs again.
+*/
+fn is_version_2(body: &[u8]) -> bool {
+    let entities_start = 4 + LEGACY_BLOCKS_LEN + 4;
+    if body.len() < entities_start || body[0..4] != 2u32.to_le_bytes() {
+        return false;
+    }
+    let entities_len =
+        u32::from_le_bytes(body[entities_start - 4..entities_start].try_into().unwrap()) as usize;
+    return body.len() == entities_start + entities_len;
+}
+
+/**
+Whether a chunk blob that starts with `version` has already been upgraded by the migration from `from`,
+like `is_version_2` for blobs that start with their version. Any other version is an error.
+*/
+fn is_upgraded(version: u32, from: u32) -> Result<bool, gamemap::WorldLoadError> {
+    return match version {
+        version if version == from => Ok(false),
+        version if version == from + 1 => Ok(true),
+        version => Err(gamemap::WorldLoadError::Malformed(format!(
+            "chunk in save format version {} while upgrading from version {}",
+            version, from
+        ))),
+    };
+}
+
+/**
+Upgrade every chunk blob of a world's save folder with `upgrade`, which returns the upgraded blob,
+or None for a blob that is already upgraded. Each region file is read once, and rewritten once if it changed.
+*/
+fn upgrade_chunks(
+    world_dir: &Path,
+    mut upgrade: impl FnMut(&[u8]) -> Result<Option<Vec<u8>>, gamemap::WorldLoadError>,
+) -> Result<(), gamemap::WorldLoadError> {
+    for region in region::saved_regions(world_dir)? {
+        let mut region_file = region::RegionFile::open(&region::region_path(world_dir, region))?;
+        let mut changed = false;
+        for index in region_file.chunk_indices() {
+            if let Some(data) = upgrade(region_file.read_chunk(index).unwrap())? {
+                region_file.write_chunk(index, data);
+                changed = true;
+            }
+        }
+        if changed {
+            region_file.save()?;
+        }
+    }
+    return Ok(());
+}
+
+/// Version 1 to 2: add the version before the blocks of every chunk blob.
+/// The rest of the blob is unchanged.
+fn migrate_1_to_2(world_dir: &Path) -> Result<(), gamemap::WorldLoadError> {
+    return upgrade_chunks(world_dir, |data| {
+        let mut body = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut body)?;
+        if is_version_2(&body) {
+            return Ok(None);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&2u32.to_le_bytes())?;
+        encoder.write_all(&body)?;
+        return Ok(Some(encoder.finish()?));
+    });
+}
+
+/**
+Version 2 to 3: store the blocks of every chunk blob in sections.
+Chunks were `gamemap::LEGACY_WORLD_HEIGHT` high, with blocks as little-endian `i32`s in x, y, z order.
+In version 3, the number of sections is a little-endian `u32`, then every section from the bottom is a byte,
+0 for a section with only air, or 1 followed by its blocks as little-endian `i32`s in x, y, z order.
+*/
+fn migrate_2_to_3(world_dir: &Path) -> Result<(), gamemap::WorldLoadError> {
+    let size = gamemap::CHUNK_SIZE;
+    let section_count = gamemap::LEGACY_WORLD_HEIGHT / sections::SECTION_HEIGHT;
+    return upgrade_chunks(world_dir, |data| {
+        let mut decoder = ZlibDecoder::new(data);
+        let mut buffer = [0u8; 4];
+        decoder.read_exact(&mut buffer)?;
+        if is_upgraded(u32::from_le_bytes(buffer), 2)? {
+            return Ok(None);
+        }
+        // Blocks of each section, in x, y, z order.
+        let mut section_blocks = vec![Vec::new(); section_count];
+        for _x in 0..size {
+            for y in 0..gamemap::LEGACY_WORLD_HEIGHT {
+                for _z in 0..size {
+                    decoder.read_exact(&mut buffer)?;
+                    section_blocks[y / sections::SECTION_HEIGHT].push(i32::from_le_bytes(buffer));
+                }
+            }
+        }
+        let mut entities = Vec::new();
+        decoder.read_to_end(&mut entities)?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&3u32.to_le_bytes())?;
+        encoder.write_all(&(section_count as u32).to_le_bytes())?;
+        for blocks in section_blocks {
+            if blocks.iter().all(|&block_id| block_id == blocks::AIR) {
+                encoder.write_all(&[0])?;
+                continue;
+            }
+            encoder.write_all(&[1])?;
+            for block_id in blocks {
+                encoder.write_all(&block_id.to_le_bytes())?;
+            }
+        }
+        encoder.write_all(&entities)?;
+        return Ok(Some(encoder.finish()?));
+    });
+}
+
+/**
+Write the blocks of a section (in x, y, z order) as in version 4: the length of its palette as a little-endian `u16`,
+the palette (its distinct blocks, in order of first appearance) as little-endian `i32`s,
+then the number of bits of each index into the palette as a byte (the fewest that fit, 0 for a single block),
+and the indices packed into little-endian `u64`s, from the lowest bits, as many whole indices as fit in each.
+This is the layout of `sections::Section::write` in version 4, kept here in case that layout changes.
+*/
+fn write_section_v4(blocks: &[i32], mut writer: impl Write) -> std::io::Result<()> {
+    let mut palette: Vec<i32> = Vec::new();
+    let mut indices = Vec::with_capacity(blocks.len());
+    for &block_id in blocks {
+        let index = match palette.iter().position(|&id| id == block_id) {
+            Some(index) => index,
+            None => {
+                palette.push(block_id);
+                palette.len() - 1
+            }
+        };
+        indices.push(index as u64);
+    }
+    let mut bits = 0;
+    while palette.len() > 1 << bits {
+        bits += 1;
+    }
+    writer.write_all(&(palette.len() as u16).to_le_bytes())?;
+    for block_id in palette.iter() {
+        writer.write_all(&block_id.to_le_bytes())?;
+    }
+    writer.write_all(&[bits as u8])?;
+    if bits == 0 {
+        return Ok(());
+    }
+    for word_indices in indices.chunks(64 / bits) {
+        let mut word = 0u64;
+        for (i, index) in word_indices.iter().enumerate() {
+            word |= index << (i * bits);
+        }
+        writer.write_all(&word.to_le_bytes())?;
+    }
+    return Ok(());
+}
+
+/**
+Version 3 to 4: compress the sections of every chunk blob with palettes (see `write_section_v4`).
+The number of sections is unchanged.
+*/
+fn migrate_3_to_4(world_dir: &Path) -> Result<(), gamemap::WorldLoadError> {
+    return upgrade_chunks(world_dir, |data| {
+        let mut decoder = ZlibDecoder::new(data);
+        let mut buffer = [0u8; 4];
+        decoder.read_exact(&mut buffer)?;
+        if is_upgraded(u32::from_le_bytes(buffer), 3)? {
+            return Ok(None);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&4u32.to_le_bytes())?;
+        decoder.read_exact(&mut buffer)?;
+        encoder.write_all(&buffer)?;
+        let section_count = u32::from_le_bytes(buffer) as usize;
+        for _ in 0..section_count {
+            let mut flag = [0u8; 1];
+            decoder.read_exact(&mut flag)?;
+            if flag[0] == 0 {
+                // Only air: a palette of one block, and no indices.
+                write_section_v4(&[blocks::AIR], &mut encoder)?;
+                continue;
+            }
+            let mut blocks = Vec::with_capacity(sections::SECTION_VOLUME);
+            for _ in 0..sections::SECTION_VOLUME {
+                decoder.read_exact(&mut buffer)?;
+                blocks.push(i32::from_le_bytes(buffer));
+            }
+            write_section_v4(&blocks, &mut encoder)?;
+        }
+        let mut entities = Vec::new();
+        decoder.read_to_end(&mut entities)?;
+        encoder.write_all(&entities)?;
+        return Ok(Some(encoder.finish()?));
+    });
+}
+
+/// Version 4 to 5: add an empty list of scheduled ticks to every chunk blob, which is its length (0) as a little-endian `u32`.
+fn migrate_4_to_5(world_dir: &Path) -> Result<(), gamemap::WorldLoadError> {
+    return upgrade_chunks(world_dir, |data| {
+        let mut decoder = ZlibDecoder::new(data);
+        let mut buffer = [0u8; 4];
+        decoder.read_exact(&mut buffer)?;
+        if is_upgraded(u32::from_le_bytes(buffer), 4)? {
+            return Ok(None);
+        }
+        let mut body = Vec::new();
+        decoder.read_to_end(&mut body)?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&5u32.to_le_bytes())?;
+        encoder.write_all(&body)?;
+        encoder.write_all(&0u32.to_le_bytes())?;
+        return Ok(Some(encoder.finish()?));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use region::tests::TestWorld;
+
+    /// The blocks of the test chunks, in a world of the legacy height: stone, dirt and grass, and glowstone in the air.
+    fn test_blocks(block_registry: &blocks::BlockRegistry) -> sections::ChunkBlocks {
+        let mut chunk_blocks = sections::ChunkBlocks::new(gamemap::LEGACY_WORLD_HEIGHT);
+        for x in 0..gamemap::CHUNK_SIZE {
+            for z in 0..gamemap::CHUNK_SIZE {
+                for (y, name) in [(0, "stone"), (8, "stone"), (9, "dirt"), (10, "grass_block")] {
+                    chunk_blocks.set(x, y, z, block_registry.id(name).unwrap());
+                }
+            }
+        }
+        chunk_blocks.set(3, 20, 5, block_registry.id("glowstone").unwrap());
+        return chunk_blocks;
+    }
+
+    /// The entities of the test chunks, as saved: their length as a little-endian `u32`, then their JSON.
+    fn test_entities() -> Vec<u8> {
+        let entities = serde_json::json!([{
+            "entity_type": "Creeper",
+            "health": 20,
+            "position": [4.5, 11.0, 6.5],
+            "rotation": 1.0,
+            "scaling": [1.0, 1.0, 1.0],
+            "velocity": [0.0, 0.0, 0.0],
+            "attack_cd": 0.0,
+        }])
+        .to_string()
+        .into_bytes();
+        let mut data = (entities.len() as u32).to_le_bytes().to_vec();
+        data.extend(entities);
+        return data;
+    }
+
+    /**
+    The blocks of the test chunk as saved in versions 4 and 5, encoded by hand rather than by the encoders of the game,
+    so that the test notices if they change: the number of sections, then every section with the length of its palette,
+    the palette, the bits of each index into the palette, and the indices packed into `u64`s from the lowest bits.
+    */
+    fn test_sections_v4(block_registry: &blocks::BlockRegistry) -> Vec<u8> {
+        let mut data = 2u32.to_le_bytes().to_vec();
+        // The bottom section, with 2 bits per index. Each `u64` holds two rows of 16 blocks along z,
+        // at y and y + 1, and all the blocks of a row are the same.
+        let palette = [
+            block_registry.id("stone").unwrap(),
+            blocks::AIR,
+            block_registry.id("dirt").unwrap(),
+            block_registry.id("grass_block").unwrap(),
+        ];
+        data.extend((palette.len() as u16).to_le_bytes());
+        for block_id in palette {
+            data.extend(block_id.to_le_bytes());
+        }
+        data.push(2);
+        let row = |index: u64| index * 0x5555_5555;
+        // Palette indices of the rows from y = 0 up, two by two: stone at 0 and 8, dirt at 9 and grass at 10.
+        let rows = [(0, 1), (1, 1), (1, 1), (1, 1), (0, 2), (3, 1), (1, 1), (1, 1)];
+        for _x in 0..gamemap::CHUNK_SIZE {
+            for (lower, upper) in rows {
+                data.extend((row(lower) | row(upper) << 32).to_le_bytes());
+            }
+        }
+        // The section above, with 1 bit per index, 64 in each `u64`: glowstone at (3, 4, 5) in air.
+        data.extend(2u16.to_le_bytes());
+        data.extend(blocks::AIR.to_le_bytes());
+        data.extend(block_registry.id("glowstone").unwrap().to_le_bytes());
+        data.push(1);
+        let glowstone_index = (3 * 16 + 4) * 16 + 5;
+        for word in 0..sections::SECTION_VOLUME / 64 {
+            let bits: u64 = if word == glowstone_index / 64 { 1 << (glowstone_index % 64) } else { 0 };
+            data.extend(bits.to_le_bytes());
+        }
+        return data;
+    }
+
+    /// A chunk blob of the test chunk, as saved in a save format version from 1 to 5.
+    fn test_chunk_data(version: u32, block_registry: &blocks::BlockRegistry) -> Vec<u8> {
+        let chunk_blocks = test_blocks(block_registry);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        if version >= 2 {
+            encoder.write_all(&version.to_le_bytes()).unwrap();
+        }
+        match version {
+            1 | 2 => {
+                for x in 0..gamemap::CHUNK_SIZE {
+                    for y in 0..gamemap::LEGACY_WORLD_HEIGHT {
+                        for z in 0..gamemap::CHUNK_SIZE {
+                            encoder.write_all(&chunk_blocks.get(x, y, z).to_le_bytes()).unwrap();
+                        }
+                    }
+                }
+            }
+            3 => {
+                encoder.write_all(&(chunk_blocks.sections().len() as u32).to_le_bytes()).unwrap();
+                for section in chunk_blocks.sections() {
+                    encoder.write_all(&[1]).unwrap();
+                    for block_id in section.blocks() {
+                        encoder.write_all(&block_id.to_le_bytes()).unwrap();
+                    }
+                }
+            }
+            _ => encoder.write_all(&test_sections_v4(block_registry)).unwrap(),
+        }
+        encoder.write_all(&test_entities()).unwrap();
+        if version >= 5 {
+            // No scheduled ticks.
+            encoder.write_all(&0u32.to_le_bytes()).unwrap();
+        }
+        return encoder.finish().unwrap();
+    }
+
+    /// Save a world in a save format version from 1 to 4, with the test chunk at (0, 0),
+    /// and at (1, 0) the test chunk already upgraded to the next version, as left by an interrupted migration.
+    fn write_world(test_world: &TestWorld, version: u32, block_registry: &blocks::BlockRegistry) {
+        let world_dir = test_world.dir();
+        std::fs::create_dir_all(&world_dir).unwrap();
+        let mut level = serde_json::json!({ "block_palette": block_registry.palette() });
+        if version >= 2 {
+            level["format_version"] = version.into();
+        }
+        std::fs::write(world_dir.join("level.json"), level.to_string()).unwrap();
+        region::save_chunk_data(&world_dir, (0, 0), test_chunk_data(version, block_registry)).unwrap();
+        region::save_chunk_data(&world_dir, (1, 0), test_chunk_data(version + 1, block_registry)).unwrap();
+    }
+
+    /// Check that a chunk of a migrated world has the blocks and entities of the test chunk.
+    fn assert_test_chunk(world_dir: &Path, chunk_key: (i32, i32), block_registry: &blocks::BlockRegistry) {
+        let chunk = region::load_chunk(world_dir, chunk_key).unwrap().unwrap();
+        let chunk_blocks = chunk.blocks.lock().unwrap();
+        let expected_blocks = test_blocks(block_registry);
+        assert_eq!(chunk_blocks.height(), gamemap::LEGACY_WORLD_HEIGHT);
+        for (section, expected_section) in chunk_blocks.sections().iter().zip(expected_blocks.sections()) {
+            assert_eq!(section.blocks(), expected_section.blocks());
+        }
+        assert_eq!(chunk.entities.len(), 1);
+        let entity = chunk.entities[0].lock().unwrap();
+        assert_eq!(entity.entity_type, "Creeper");
+        assert_eq!(entity.position, bevy::prelude::Vec3::new(4.5, 11.0, 6.5));
+        assert_eq!(entity.rotation, 1.0);
+        assert!(chunk.scheduled_ticks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn worlds_of_every_version_are_migrated() {
+        let block_registry = blocks::BlockRegistry::default();
+        for version in 1..SAVE_FORMAT_VERSION {
+            let test_world = TestWorld::new(&format!("test-migration-v{}", version));
+            write_world(&test_world, version, &block_registry);
+            migrate_world(&test_world.saves_dir, &test_world.name, &block_registry).unwrap();
+
+            assert_eq!(read_format_version(&test_world.dir()).unwrap(), SAVE_FORMAT_VERSION);
+            assert_test_chunk(&test_world.dir(), (0, 0), &block_registry);
+            assert_test_chunk(&test_world.dir(), (1, 0), &block_registry);
+            // The world was backed up before migrating.
+            assert_eq!(region::list_backups(&test_world.saves_dir, &test_world.name).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn legacy_save_is_migrated() {
+        let block_registry = blocks::BlockRegistry::default();
+        let test_world = TestWorld::new("test-migration-v0");
+        region::tests::write_legacy_save(&test_world);
+        migrate_world(&test_world.saves_dir, &test_world.name, &block_registry).unwrap();
+
+        assert_eq!(read_format_version(&test_world.dir()).unwrap(), SAVE_FORMAT_VERSION);
+        let chunk = region::load_chunk(&test_world.dir(), (0, 0)).unwrap().unwrap();
+        let chunk_blocks = chunk.blocks.lock().unwrap();
+        assert_eq!(chunk_blocks.get(4, 9, 4), block_registry.id("stone").unwrap());
+        assert_eq!(chunk_blocks.get(4, 10, 4), block_registry.id("grass_block").unwrap());
+        assert_eq!(chunk_blocks.get(4, 11, 4), blocks::AIR);
+        assert_eq!(chunk.entities[0].lock().unwrap().entity_type, "MainPlayer");
+    }
+
+    #[test]
+    fn format_version_0_is_malformed() {
+        let block_registry = blocks::BlockRegistry::default();
+        let test_world = TestWorld::new("test-migration-version-0");
+        std::fs::create_dir_all(test_world.dir()).unwrap();
+        std::fs::write(test_world.dir().join("level.json"), r#"{"format_version": 0}"#).unwrap();
+        assert!(matches!(
+            migrate_world(&test_world.saves_dir, &test_world.name, &block_registry),
+            Err(gamemap::WorldLoadError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+//! Ore generation
+//! ---
+//! Ores are placed in veins during chunk generation, replacing stone only.
+//! Each vein starts at a random position of a chunk and grows by a random walk,
+//! so it may reach into neighbor chunks. All random numbers of a chunk's veins
+//! come from the world seed and the chunk's coordinates, so every chunk places
+//! the same veins no matter in which order chunks are generated.
+
+use crate::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Salt of the seeds of ore veins (see `gamemap::chunk_seed`). Each kind of ore adds its index.
+const ORE_SALT: u64 = 100;
+
+/// A kind of ore vein.
+pub struct OreVein {
+    /// The ore block's string ID.
+    pub block: &'static str,
+    /// Number of steps of the random walk of a vein, i.e. at most how many blocks a vein has.
+    pub vein_size: usize,
+    /// Number of veins starting in each chunk.
+    pub veins_per_chunk: usize,
+    /// Veins start between these heights (inclusive), and never leave them.
+    pub min_height: usize,
+    pub max_height: usize,
+}
+
+impl OreVein {
+    pub fn new(
+        block: &'static str,
+        vein_size: usize,
+        veins_per_chunk: usize,
+        min_height: usize,
+        max_height: usize,
+    ) -> OreVein {
+        return OreVein {
+            block,
+            vein_size,
+            veins_per_chunk,
+            min_height,
+            max_height,
+        };
+    }
+}
+
+/**
+The ore veins of the default world generation in a world of the height.
+Rarer ores are deeper and in smaller veins. Coal is found up to the top of the world.
+*/
+pub fn default_ore_veins(height: usize) -> Vec<OreVein> {
+    let top = height - 1;
+    return vec![
+        OreVein::new("coal_ore", 12, 8, 1, top),
+        OreVein::new("copper_ore", 8, 4, 8, 28.min(top)),
+        OreVein::new("iron_ore", 8, 6, 1, 24.min(top)),
+        OreVein::new("gold_ore", 7, 2, 1, 14.min(top)),
+        OreVein::new("redstone_ore", 6, 3, 1, 10.min(top)),
+        OreVein::new("lapis_ore", 5, 1, 1, 12.min(top)),
+        OreVein::new("diamond_ore", 4, 1, 1, 8.min(top)),
+    ];
+}
+
+/**
+Place ore veins into the blocks of a chunk, including the parts of veins starting in neighbor chunks.
+Only stone is replaced.
+*/
+pub fn place_ores(
+    blocks: &mut sections::ChunkBlocks,
+    chunk_key: (i32, i32),
+    seed: u64,
+    ore_veins: &[OreVein],
+    block_registry: &blocks::BlockRegistry,
+) {
+    let size = gamemap::CHUNK_SIZE as i32;
+    let stone = block_registry.id("stone").unwrap();
+    for (i, vein) in ore_veins.iter().enumerate() {
+        let ore = block_registry.id(vein.block).unwrap();
+        for dx in -1..=1 {
+            for dz in -1..=1 {
+                let origin_chunk = (chunk_key.0 + dx, chunk_key.1 + dz);
+                let mut rng = StdRng::seed_from_u64(gamemap::chunk_seed(
+                    seed,
+                    origin_chunk,
+                    ORE_SALT + i as u64,
+                ));
+                for _ in 0..vein.veins_per_chunk {
+                    // Coordinates are relative to this chunk.
+                    let mut x = dx * size + rng.gen_range(0, size);
+                    let mut y = rng.gen_range(vein.min_height, vein.max_height + 1) as i32;
+                    let mut z = dz * size + rng.gen_range(0, size);
+                    for _ in 0..vein.vein_size {
+                        if 0 <= x
+                            && x < size
+                            && 0 <= z
+                            && z < size
+                            && blocks.get(x as usize, y as usize, z as usize) == stone
+                        {
+                            blocks.set(x as usize, y as usize, z as usize, ore);
+                        }
+                        match rng.gen_range(0, 6) {
+                            0 => x += 1,
+                            1 => x -= 1,
+                            2 => z += 1,
+                            3 => z -= 1,
+                            4 => y += 1,
+                            _ => y -= 1,
+                        }
+                        y = y.clamp(vein.min_height as i32, vein.max_height as i32);
+                    }
+                }
+            }
+        }
+    }
+}
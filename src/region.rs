@@ -0,0 +1,677 @@
+//! Binary region-file save format
+//! ---
+//! A world is saved as a folder `./saves/<world name>/`:
+//! - `level.json`: world data other than chunks, e.g. the block palette.
+//! - `region/r.<region x>.<region z>.bin`: chunks of a `REGION_SIZE * REGION_SIZE` region.
+//!
+//! A region file starts with a header: the magic `RMCR`, then an offset table with
+//! `(offset: u32, length: u32)` for every chunk in the region (offset 0 means the chunk is absent).
+//! Each chunk is stored as a zlib-compressed blob, so it can be decoded independently.
+//! A single chunk is saved by appending its blob to the file, then pointing its header entry to it,
+//! so a crash while saving leaves the old blob in use (see `save_chunk_data`).
+//! Saving many chunks rewrites each file as a whole, packed, through a temporary file (see `write_atomically`).
+//! The blob starts with the save format version it was written in (see the `migration` module).
+//!
+//! Old saves (a single `./saves/<world name>.json`) are converted by `convert_legacy_save`.
+//!
+//! Backups of a world are copies of its folder in `./saves/backups/<world name>/<time>/`.
+//! When a backup is opened, the world it replaces is kept in `./saves/backups/<world name>/broken-<time>/`.
+
+use crate::*;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use walkdir::WalkDir;
+
+/// Number of chunks in a row of a region.
+pub const REGION_SIZE: i32 = 8;
+const REGION_MAGIC: &[u8; 4] = b"RMCR";
+const HEADER_LEN: u64 = 4 + 8 * (REGION_SIZE * REGION_SIZE) as u64;
+
+/// The folder of the game's saves, which is `saves_dir` of the functions below.
+pub const SAVES_DIR: &str = "./saves";
+
+/// The folder of a world's save.
+pub fn world_dir(saves_dir: &Path, world_name: &str) -> PathBuf {
+    return saves_dir.join(world_name);
+}
+
+/// The path of a world's save in the old single-JSON format.
+pub fn legacy_save_path(saves_dir: &Path, world_name: &str) -> PathBuf {
+    return saves_dir.join(format!("{}.json", world_name));
+}
+
+/// The folder an old single-JSON save is converted in, before it replaces the world's folder.
+fn converting_dir(saves_dir: &Path, world_name: &str) -> PathBuf {
+    return saves_dir.join(format!("{}.converting", world_name));
+}
+
+/// The folder of a world's backups.
+pub fn backups_dir(saves_dir: &Path, world_name: &str) -> PathBuf {
+    return saves_dir.join("backups").join(world_name);
+}
+
+/// Returns the region that a chunk belongs to, and the chunk's index in the region's offset table.
+fn locate_chunk(chunk_key: (i32, i32)) -> ((i32, i32), usize) {
+    let region = (
+        chunk_key.0.div_euclid(REGION_SIZE),
+        chunk_key.1.div_euclid(REGION_SIZE),
+    );
+    let local_x = chunk_key.0.rem_euclid(REGION_SIZE);
+    let local_z = chunk_key.1.rem_euclid(REGION_SIZE);
+    return (region, (local_x * REGION_SIZE + local_z) as usize);
+}
+
+pub fn region_path(world_dir: &Path, region: (i32, i32)) -> PathBuf {
+    return world_dir
+        .join("region")
+        .join(format!("r.{}.{}.bin", region.0, region.1));
+}
+
+fn invalid_data(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+/// Parse the header of a region file into the offset and length of every chunk (offset 0 if it is absent).
+fn parse_header(header: &[u8], path: &Path) -> io::Result<Vec<(usize, usize)>> {
+    if header.len() < HEADER_LEN as usize || &header[0..4] != REGION_MAGIC {
+        return Err(invalid_data(format!("{:?} is not a region file", path)));
+    }
+    return Ok(header[4..HEADER_LEN as usize]
+        .chunks_exact(8)
+        .map(|entry| {
+            (
+                u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize,
+                u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize,
+            )
+        })
+        .collect());
+}
+
+/// Read the header of an open region file, leaving the file after it.
+fn read_header(file: &mut File, path: &Path) -> io::Result<Vec<(usize, usize)>> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    file.take(HEADER_LEN).read_to_end(&mut header)?;
+    return parse_header(&header, path);
+}
+
+/**
+Write a file atomically: the data is written to `<path>.tmp` first, which then replaces the file.
+A crash in the middle of writing leaves the old file intact.
+*/
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    return std::fs::rename(temp_path, path);
+}
+
+/// A region file read into memory. Changes are written by `save`.
+pub struct RegionFile {
+    path: PathBuf,
+    /// The raw (compressed) data of every chunk in the region.
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl RegionFile {
+    /// Read a region file. Returns an empty region if it does not exist.
+    pub fn open(path: &Path) -> io::Result<RegionFile> {
+        let mut chunks = vec![None; (REGION_SIZE * REGION_SIZE) as usize];
+        if !path.try_exists()? {
+            return Ok(RegionFile {
+                path: path.to_path_buf(),
+                chunks,
+            });
+        }
+        let data = std::fs::read(path)?;
+        for (i, (offset, length)) in parse_header(&data, path)?.into_iter().enumerate() {
+            if offset == 0 {
+                continue;
+            }
+            match data.get(offset..offset + length) {
+                Some(chunk_data) => chunks[i] = Some(chunk_data.to_vec()),
+                None => return Err(invalid_data(format!("{:?} is truncated", path))),
+            }
+        }
+        return Ok(RegionFile {
+            path: path.to_path_buf(),
+            chunks,
+        });
+    }
+
+    /// Indices of all chunks stored in this region.
+    pub fn chunk_indices(&self) -> Vec<usize> {
+        return (0..self.chunks.len())
+            .filter(|&i| self.chunks[i].is_some())
+            .collect();
+    }
+
+    /// The raw (compressed) data of a chunk. Returns None if the chunk is absent.
+    pub fn read_chunk(&self, index: usize) -> Option<&Vec<u8>> {
+        return self.chunks[index].as_ref();
+    }
+
+    /// Replace the raw (compressed) data of a chunk. It is written to disk by `save`.
+    pub fn write_chunk(&mut self, index: usize, data: Vec<u8>) {
+        self.chunks[index] = Some(data);
+    }
+
+    /// Write the whole region file atomically, with chunks packed one after another.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut header = REGION_MAGIC.to_vec();
+        let mut body: Vec<u8> = Vec::new();
+        for chunk_data in self.chunks.iter() {
+            match chunk_data {
+                Some(chunk_data) => {
+                    header.extend((HEADER_LEN as u32 + body.len() as u32).to_le_bytes());
+                    header.extend((chunk_data.len() as u32).to_le_bytes());
+                    body.extend(chunk_data);
+                }
+                None => header.extend([0; 8]),
+            }
+        }
+        header.extend(body);
+        return write_atomically(&self.path, &header);
+    }
+}
+
+/**
+Write the blocks of a chunk: the number of sections as a little-endian `u32`,
+then every section from the bottom, compacted (see `sections::Section::write`).
+*/
+pub fn write_blocks(blocks: &sections::ChunkBlocks, mut writer: impl Write) -> io::Result<()> {
+    writer.write_all(&(blocks.sections().len() as u32).to_le_bytes())?;
+    for section in blocks.sections() {
+        section.compacted().write(&mut writer)?;
+    }
+    return Ok(());
+}
+
+/// Read the blocks of a chunk written by `write_blocks`.
+pub fn read_blocks(mut reader: impl Read) -> io::Result<sections::ChunkBlocks> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    let section_count = u32::from_le_bytes(buffer) as usize;
+    if section_count * sections::SECTION_HEIGHT > gamemap::MAX_WORLD_HEIGHT {
+        return Err(invalid_data(format!("chunk has {} sections", section_count)));
+    }
+    let mut chunk_sections = Vec::with_capacity(section_count);
+    for _ in 0..section_count {
+        chunk_sections.push(sections::Section::read(&mut reader)?);
+    }
+    return Ok(sections::ChunkBlocks::from_sections(chunk_sections));
+}
+
+/**
+Encode a chunk into compressed bytes.
+The save format version is stored as a little-endian `u32`,
+then blocks (see `write_blocks`), followed by entities in JSON,
+and scheduled ticks (see `write_scheduled_ticks`).
+*/
+pub fn encode_chunk(chunk: &gamemap::Chunk) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&migration::SAVE_FORMAT_VERSION.to_le_bytes())?;
+    write_blocks(&chunk.blocks.lock().unwrap(), &mut encoder)?;
+    let serialized_entities = serde_json::to_vec(&chunk.entities)?;
+    encoder.write_all(&(serialized_entities.len() as u32).to_le_bytes())?;
+    encoder.write_all(&serialized_entities)?;
+    write_scheduled_ticks(&chunk.scheduled_ticks.lock().unwrap(), &mut encoder)?;
+    return encoder.finish();
+}
+
+/**
+Write the scheduled ticks of a chunk: their number as a little-endian `u32`,
+then for each tick its local x (`u8`), y (little-endian `u16`), z (`u8`)
+and the game tick it is due (little-endian `u64`).
+*/
+pub fn write_scheduled_ticks(
+    scheduled_ticks: &[block_updates::ScheduledTick],
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writer.write_all(&(scheduled_ticks.len() as u32).to_le_bytes())?;
+    for tick in scheduled_ticks {
+        writer.write_all(&[tick.x])?;
+        writer.write_all(&tick.y.to_le_bytes())?;
+        writer.write_all(&[tick.z])?;
+        writer.write_all(&tick.due.to_le_bytes())?;
+    }
+    return Ok(());
+}
+
+/// Read scheduled ticks written by `write_scheduled_ticks`.
+fn read_scheduled_ticks(mut reader: impl Read) -> io::Result<Vec<block_updates::ScheduledTick>> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    let count = u32::from_le_bytes(buffer) as usize;
+    let mut scheduled_ticks = Vec::new();
+    for _ in 0..count {
+        let mut tick = [0u8; 12];
+        reader.read_exact(&mut tick)?;
+        let (x, z) = (tick[0], tick[3]);
+        if x as usize >= gamemap::CHUNK_SIZE || z as usize >= gamemap::CHUNK_SIZE {
+            return Err(invalid_data(format!(
+                "scheduled tick at ({}, {}) is outside the chunk",
+                x, z
+            )));
+        }
+        scheduled_ticks.push(block_updates::ScheduledTick {
+            x,
+            y: u16::from_le_bytes([tick[1], tick[2]]),
+            z,
+            due: u64::from_le_bytes(tick[4..12].try_into().unwrap()),
+        });
+    }
+    return Ok(scheduled_ticks);
+}
+
+/// Decode a chunk from bytes encoded by `encode_chunk`.
+/// Chunks written in another save format version must be migrated first.
+pub fn decode_chunk(data: &[u8]) -> io::Result<gamemap::Chunk> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut buffer = [0u8; 4];
+    decoder.read_exact(&mut buffer)?;
+    let version = u32::from_le_bytes(buffer);
+    if version != migration::SAVE_FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "chunk is in save format version {}, expected {}",
+            version,
+            migration::SAVE_FORMAT_VERSION
+        )));
+    }
+    return decode_chunk_body(decoder);
+}
+
+/// Decode the blocks, entities and scheduled ticks of a chunk, after the version.
+fn decode_chunk_body(mut decoder: impl Read) -> io::Result<gamemap::Chunk> {
+    let blocks = read_blocks(&mut decoder)?;
+    let mut buffer = [0u8; 4];
+    decoder.read_exact(&mut buffer)?;
+    let mut serialized_entities = vec![0; u32::from_le_bytes(buffer) as usize];
+    decoder.read_exact(&mut serialized_entities)?;
+    let entities: Vec<Arc<Mutex<entities::EntityStatus>>> =
+        serde_json::from_slice(&serialized_entities)?;
+    let scheduled_ticks = read_scheduled_ticks(&mut decoder)?;
+    return Ok(gamemap::Chunk {
+        blocks: Arc::new(Mutex::new(blocks)),
+        entities,
+        scheduled_ticks: Mutex::new(scheduled_ticks),
+    });
+}
+
+/**
+Save the raw (compressed) data of a single chunk into its region file, without rewriting the other chunks:
+the data is appended to the file and synced, then the chunk's header entry is pointed to it.
+The old data of the chunk is left unused. A file that is more than half unused is rewritten as a whole instead,
+which packs it again. So is a file that does not exist yet.
+*/
+pub fn save_chunk_data(world_dir: &Path, chunk_key: (i32, i32), data: Vec<u8>) -> io::Result<()> {
+    let (region, index) = locate_chunk(chunk_key);
+    let path = region_path(world_dir, region);
+    let rewrite = |data: Vec<u8>| -> io::Result<()> {
+        let mut region_file = RegionFile::open(&path)?;
+        region_file.write_chunk(index, data);
+        return region_file.save();
+    };
+    if !path.try_exists()? {
+        return rewrite(data);
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let header = read_header(&mut file, &path)?;
+    let used_len: usize = header.iter().map(|&(_offset, length)| length).sum();
+    let offset = file.seek(SeekFrom::End(0))?;
+    let new_len = offset + data.len() as u64;
+    if new_len > 2 * (HEADER_LEN + (used_len + data.len()) as u64) || new_len > u32::MAX as u64 {
+        drop(file);
+        return rewrite(data);
+    }
+    file.write_all(&data)?;
+    file.sync_data()?;
+    let mut entry = (offset as u32).to_le_bytes().to_vec();
+    entry.extend((data.len() as u32).to_le_bytes());
+    file.seek(SeekFrom::Start(4 + 8 * index as u64))?;
+    file.write_all(&entry)?;
+    return file.sync_data();
+}
+
+/// Load the raw (compressed) data of a single chunk from its region file, reading only its header and the chunk.
+/// Returns None if the chunk has never been saved.
+pub fn load_chunk_data(world_dir: &Path, chunk_key: (i32, i32)) -> io::Result<Option<Vec<u8>>> {
+    let (region, index) = locate_chunk(chunk_key);
+    let path = region_path(world_dir, region);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let (offset, length) = read_header(&mut file, &path)?[index];
+    if offset == 0 {
+        return Ok(None);
+    }
+    let mut data = vec![0; length];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    return match file.read_exact(&mut data) {
+        Ok(()) => Ok(Some(data)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(invalid_data(format!("{:?} is truncated", path)))
+        }
+        Err(err) => Err(err),
+    };
+}
+
+/// Save a single chunk into its region file.
+pub fn save_chunk(world_dir: &Path, chunk_key: (i32, i32), chunk: &gamemap::Chunk) -> io::Result<()> {
+    return save_chunk_data(world_dir, chunk_key, encode_chunk(chunk)?);
+}
+
+/// Save many chunks, writing each region file only once.
+pub fn save_chunks<'a>(
+    world_dir: &Path,
+    chunks: impl Iterator<Item = (&'a (i32, i32), &'a gamemap::Chunk)>,
+) -> io::Result<()> {
+    let mut region_files: HashMap<(i32, i32), RegionFile> = HashMap::new();
+    for (&chunk_key, chunk) in chunks {
+        let (region, index) = locate_chunk(chunk_key);
+        if !region_files.contains_key(&region) {
+            region_files.insert(region, RegionFile::open(&region_path(world_dir, region))?);
+        }
+        region_files
+            .get_mut(&region)
+            .unwrap()
+            .write_chunk(index, encode_chunk(chunk)?);
+    }
+    for region_file in region_files.values() {
+        region_file.save()?;
+    }
+    return Ok(());
+}
+
+/// Load a single chunk from its region file. Returns None if the chunk has never been saved.
+pub fn load_chunk(world_dir: &Path, chunk_key: (i32, i32)) -> io::Result<Option<gamemap::Chunk>> {
+    return match load_chunk_data(world_dir, chunk_key)? {
+        Some(data) => Ok(Some(decode_chunk(&data)?)),
+        None => Ok(None),
+    };
+}
+
+/// Returns the regions that have a region file in a world (see `region_path`).
+pub fn saved_regions(world_dir: &Path) -> io::Result<Vec<(i32, i32)>> {
+    let mut regions = Vec::new();
+    let region_dir = world_dir.join("region");
+    if !region_dir.exists() {
+        return Ok(regions);
+    }
+    for entry in std::fs::read_dir(region_dir)? {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+        // File names look like "r.-1.2.bin".
+        let parts: Vec<&str> = file_name.split('.').collect();
+        if parts.len() != 4 || parts[0] != "r" || parts[3] != "bin" {
+            continue;
+        }
+        if let (Ok(region_x), Ok(region_z)) = (parts[1].parse::<i32>(), parts[2].parse::<i32>()) {
+            regions.push((region_x, region_z));
+        }
+    }
+    return Ok(regions);
+}
+
+/**
+Convert an old single-JSON save into the region format, with block IDs remapped to the block registry
+(saves without a block palette use `gamemap::legacy_block_palette`).
+Such saves predate world seeds, so the converted world gets a random seed, which new chunks are generated from.
+The world is written in a temporary folder, which becomes the world's folder only once it is complete,
+so that a conversion that fails is tried again at the next load.
+The old file is renamed to `<world name>.json.old` rather than deleted.
+*/
+pub fn convert_legacy_save(
+    saves_dir: &Path,
+    world_name: &str,
+    block_registry: &blocks::BlockRegistry,
+) -> Result<(), gamemap::WorldLoadError> {
+    let legacy_path = legacy_save_path(saves_dir, world_name);
+    println!("Converting old save {:?} to region format", legacy_path);
+    let serialized_gamemap = std::fs::read_to_string(&legacy_path)?;
+    let mut gamemap: gamemap::GameMap = serde_json::from_str(&serialized_gamemap)?;
+    gamemap.seed = rand::random();
+    gamemap.terrain_seeds = gamemap::terrain_seeds_from_seed(gamemap.seed);
+    println!("The converted world has seed {}", gamemap.seed);
+    let unknown_blocks = gamemap::remap_gamemap(&mut gamemap, block_registry);
+    gamemap::report_unknown_blocks(unknown_blocks);
+    let converting_dir = converting_dir(saves_dir, world_name);
+    if converting_dir.try_exists()? {
+        // Left by a conversion that failed.
+        std::fs::remove_dir_all(&converting_dir)?;
+    }
+    std::fs::create_dir_all(&converting_dir)?;
+    save_chunks(&converting_dir, gamemap.map.iter())?;
+    gamemap::save_level(&gamemap, &converting_dir)?;
+    std::fs::rename(&converting_dir, world_dir(saves_dir, world_name))?;
+    std::fs::rename(&legacy_path, legacy_path.with_extension("json.old"))?;
+    return Ok(());
+}
+
+/// Copy a folder with everything in it.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Back up a world's save folder. Returns the folder of the backup.
+pub fn create_backup(saves_dir: &Path, world_name: &str) -> io::Result<PathBuf> {
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S%3f").to_string();
+    let backup_dir = backups_dir(saves_dir, world_name).join(time);
+    copy_dir(&world_dir(saves_dir, world_name), &backup_dir)?;
+    return Ok(backup_dir);
+}
+
+/// Returns the folders of a world's backups, the latest first.
+pub fn list_backups(saves_dir: &Path, world_name: &str) -> io::Result<Vec<PathBuf>> {
+    let mut backups = Vec::new();
+    let backups_dir = backups_dir(saves_dir, world_name);
+    if !backups_dir.try_exists()? {
+        return Ok(backups);
+    }
+    for entry in std::fs::read_dir(backups_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with("broken-") {
+            backups.push(entry.path());
+        }
+    }
+    // Backup names are times, so they sort in time order.
+    backups.sort();
+    backups.reverse();
+    return Ok(backups);
+}
+
+/// Delete the oldest backups of a world, keeping at most `max_backups` of them.
+pub fn rotate_backups(saves_dir: &Path, world_name: &str, max_backups: usize) -> io::Result<()> {
+    for backup_dir in list_backups(saves_dir, world_name)?.iter().skip(max_backups) {
+        std::fs::remove_dir_all(backup_dir)?;
+    }
+    return Ok(());
+}
+
+/**
+Replace a world's save with a backup.
+The replaced save is moved to `broken-<time>` in the world's backups folder rather than deleted.
+*/
+pub fn restore_backup(saves_dir: &Path, world_name: &str, backup_dir: &Path) -> io::Result<()> {
+    let world_dir = world_dir(saves_dir, world_name);
+    if world_dir.try_exists()? {
+        let time = chrono::Local::now().format("%Y%m%d-%H%M%S%3f").to_string();
+        let backups_dir = backups_dir(saves_dir, world_name);
+        let broken_dir = backups_dir.join(format!("broken-{}", time));
+        std::fs::create_dir_all(&backups_dir)?;
+        std::fs::rename(&world_dir, &broken_dir)?;
+        println!("Moved the replaced world to {:?}", broken_dir);
+    }
+    return copy_dir(backup_dir, &world_dir);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A world saved by a test in a saves folder of its own in the temporary folder, which is deleted when it is dropped.
+    pub struct TestWorld {
+        pub saves_dir: PathBuf,
+        pub name: String,
+    }
+
+    impl TestWorld {
+        /// A world with nothing saved yet. The name must be unique among tests.
+        pub fn new(name: &str) -> TestWorld {
+            // The process ID keeps test runs at the same time apart.
+            let saves_dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&saves_dir);
+            std::fs::create_dir_all(&saves_dir).unwrap();
+            return TestWorld {
+                saves_dir,
+                name: name.to_string(),
+            };
+        }
+
+        pub fn dir(&self) -> PathBuf {
+            return world_dir(&self.saves_dir, &self.name);
+        }
+    }
+
+    impl Drop for TestWorld {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.saves_dir);
+        }
+    }
+
+    /// A baseline single-JSON save: one chunk without a block palette, with texture-folder IDs.
+    pub fn write_legacy_save(test_world: &TestWorld) {
+        // Stone up to y = 9, grass at y = 10, an ID that was never generated at y = 11, then air.
+        let column: Vec<i32> = (0..gamemap::LEGACY_WORLD_HEIGHT)
+            .map(|y| match y {
+                0..=9 => 210,
+                10 => 361,
+                11 => 5,
+                _ => -1,
+            })
+            .collect();
+        let blocks: Vec<Vec<Vec<i32>>> = (0..gamemap::CHUNK_SIZE)
+            .map(|_x| {
+                column
+                    .iter()
+                    .map(|&block_id| vec![block_id; gamemap::CHUNK_SIZE])
+                    .collect()
+            })
+            .collect();
+        let save = serde_json::json!({
+            "map": [[[0, 0], {
+                "blocks": blocks,
+                "entities": [{
+                    "entity_type": "MainPlayer",
+                    "health": 20,
+                    "position": [1.5, 11.0, 2.5],
+                    "rotation": 0.0,
+                    "scaling": [1.0, 1.0, 1.0],
+                    "velocity": [0.0, 0.0, 0.0],
+                    "attack_cd": 0.0,
+                }],
+            }]],
+        });
+        std::fs::write(legacy_save_path(&test_world.saves_dir, &test_world.name), save.to_string()).unwrap();
+    }
+
+    #[test]
+    fn legacy_save_is_converted_with_legacy_block_ids() {
+        let test_world = TestWorld::new("test-region-legacy");
+        write_legacy_save(&test_world);
+        let block_registry = blocks::BlockRegistry::default();
+        convert_legacy_save(&test_world.saves_dir, &test_world.name, &block_registry).unwrap();
+
+        assert!(!legacy_save_path(&test_world.saves_dir, &test_world.name).exists());
+        assert!(!converting_dir(&test_world.saves_dir, &test_world.name).exists());
+        let chunk = load_chunk(&test_world.dir(), (0, 0)).unwrap().unwrap();
+        let blocks = chunk.blocks.lock().unwrap();
+        let stone = block_registry.id("stone").unwrap();
+        let grass_block = block_registry.id("grass_block").unwrap();
+        for (x, z) in [(0, 0), (7, 3), (15, 15)] {
+            assert_eq!(blocks.get(x, 0, z), stone);
+            assert_eq!(blocks.get(x, 9, z), stone);
+            assert_eq!(blocks.get(x, 10, z), grass_block);
+            assert_eq!(blocks.get(x, 11, z), blocks::AIR); // Unknown.
+            assert_eq!(blocks.get(x, 12, z), blocks::AIR);
+        }
+        let entity = chunk.entities[0].lock().unwrap();
+        assert_eq!(entity.entity_type, "MainPlayer");
+        assert_eq!(entity.position, bevy::prelude::Vec3::new(1.5, 11.0, 2.5));
+
+        // Loading the world does not remap the converted chunks again.
+        let level = std::fs::read_to_string(test_world.dir().join("level.json")).unwrap();
+        let level: serde_json::Value = serde_json::from_str(&level).unwrap();
+        assert_eq!(
+            level["block_palette"],
+            serde_json::json!(block_registry.palette())
+        );
+    }
+
+    #[test]
+    fn legacy_save_gets_a_random_seed() {
+        let block_registry = blocks::BlockRegistry::default();
+        let mut seeds = Vec::new();
+        for world_name in ["test-region-seed-1", "test-region-seed-2"] {
+            let test_world = TestWorld::new(world_name);
+            write_legacy_save(&test_world);
+            convert_legacy_save(&test_world.saves_dir, &test_world.name, &block_registry).unwrap();
+
+            let level = std::fs::read_to_string(test_world.dir().join("level.json")).unwrap();
+            let level: serde_json::Value = serde_json::from_str(&level).unwrap();
+            let seed = level["seed"].as_u64().unwrap();
+            assert_eq!(
+                level["terrain_seeds"],
+                serde_json::json!(gamemap::terrain_seeds_from_seed(seed))
+            );
+            seeds.push(seed);
+        }
+        assert_ne!(seeds[0], seeds[1]);
+    }
+
+    #[test]
+    fn single_chunks_are_saved_in_place() {
+        let test_world = TestWorld::new("test-region-in-place");
+        let world_dir = test_world.dir();
+        let path = region_path(&world_dir, (0, 0));
+        save_chunk_data(&world_dir, (0, 0), vec![1; 100]).unwrap();
+        save_chunk_data(&world_dir, (1, 0), vec![2; 50]).unwrap();
+        save_chunk_data(&world_dir, (0, 0), vec![3; 30]).unwrap();
+
+        assert_eq!(load_chunk_data(&world_dir, (0, 0)).unwrap(), Some(vec![3; 30]));
+        assert_eq!(load_chunk_data(&world_dir, (1, 0)).unwrap(), Some(vec![2; 50]));
+        assert_eq!(load_chunk_data(&world_dir, (2, 0)).unwrap(), None);
+        assert_eq!(load_chunk_data(&world_dir, (REGION_SIZE, 0)).unwrap(), None);
+        // The new data was appended, and the old data of (0, 0) is unused.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_LEN + 100 + 50 + 30);
+
+        // A file that is mostly unused is packed again.
+        for _ in 0..100 {
+            save_chunk_data(&world_dir, (0, 0), vec![4; 30]).unwrap();
+        }
+        assert!(std::fs::metadata(&path).unwrap().len() <= 2 * (HEADER_LEN + 50 + 30));
+        assert_eq!(load_chunk_data(&world_dir, (0, 0)).unwrap(), Some(vec![4; 30]));
+        assert_eq!(load_chunk_data(&world_dir, (1, 0)).unwrap(), Some(vec![2; 50]));
+    }
+}
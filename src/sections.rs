@@ -0,0 +1,456 @@
+//! Chunk sections
+//! ---
+//! The blocks of a chunk are stored as a stack of sections, each `CHUNK_SIZE * SECTION_HEIGHT * CHUNK_SIZE` blocks.
+//! Each section is compressed with a palette of its distinct blocks (see `Section`),
+//! so a section of a single block (like the sky above the terrain) takes almost no memory nor space in the save,
+//! and a section of a few kinds of blocks takes a few bits per block.
+//!
+//! The height of a world (a multiple of `SECTION_HEIGHT`) is chosen when it is created.
+//! Blocks above the world are air, and there is nothing below y = 0.
+
+use crate::*;
+use gamemap::CHUNK_SIZE;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::{self, Read, Write};
+
+/// Number of blocks in a section vertically.
+pub const SECTION_HEIGHT: usize = 16;
+/// Number of blocks in a section.
+pub const SECTION_VOLUME: usize = CHUNK_SIZE * SECTION_HEIGHT * CHUNK_SIZE;
+
+/**
+The blocks of a section, compressed with a palette.
+The palette lists the distinct blocks of the section, and every block is stored as an index into it,
+packed into `u64`s with `bits` bits each (indices never straddle two `u64`s).
+A section of a single block (e.g. all air or all stone) has a palette of one block, `bits` 0 and no data.
+
+The palette may also contain blocks that are no longer used. They are dropped by `compacted`.
+*/
+#[derive(Clone, Debug)]
+pub struct Section {
+    palette: Vec<i32>,
+    bits: u32,
+    data: Vec<u64>,
+}
+
+/// The index of the block at (x, y, z) in a section, in x, y, z order.
+fn block_index(x: usize, y: usize, z: usize) -> usize {
+    return (x * SECTION_HEIGHT + y) * CHUNK_SIZE + z;
+}
+
+impl Section {
+    /// A section with only one kind of block.
+    pub fn single(block_id: i32) -> Section {
+        return Section {
+            palette: vec![block_id],
+            bits: 0,
+            data: Vec::new(),
+        };
+    }
+
+    /// A section of blocks given in x, y, z order (see `block_index`).
+    pub fn from_blocks(blocks: &[i32]) -> Section {
+        let mut section = Section::single(blocks[0]);
+        for (index, &block_id) in blocks.iter().enumerate() {
+            section.set_index(index, block_id);
+        }
+        return section;
+    }
+
+    /// The blocks of the section in x, y, z order.
+    pub fn blocks(&self) -> Vec<i32> {
+        return (0..SECTION_VOLUME)
+            .map(|index| self.get_index(index))
+            .collect();
+    }
+
+    /// Whether the section has only air.
+    pub fn is_air(&self) -> bool {
+        return self.palette.iter().all(|&block_id| block_id == blocks::AIR)
+            || (self.bits > 0
+                && self
+                    .blocks()
+                    .iter()
+                    .all(|&block_id| block_id == blocks::AIR));
+    }
+
+    /// Number of blocks of the section for each block of the palette (0 for blocks no longer used).
+    fn palette_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.palette.len()];
+        if self.bits == 0 {
+            counts[0] = SECTION_VOLUME;
+            return counts;
+        }
+        let per_word = self.indices_per_word();
+        let mask = (1u64 << self.bits) - 1;
+        for index in 0..SECTION_VOLUME {
+            let shift = (index % per_word) as u32 * self.bits;
+            counts[((self.data[index / per_word] >> shift) & mask) as usize] += 1;
+        }
+        return counts;
+    }
+
+    /// Number of indices in each `u64` of data.
+    fn indices_per_word(&self) -> usize {
+        return 64 / self.bits as usize;
+    }
+
+    fn get_index(&self, index: usize) -> i32 {
+        if self.bits == 0 {
+            return self.palette[0];
+        }
+        let per_word = self.indices_per_word();
+        let word = self.data[index / per_word];
+        let shift = (index % per_word) as u32 * self.bits;
+        let palette_index = (word >> shift) & ((1 << self.bits) - 1);
+        return self.palette[palette_index as usize];
+    }
+
+    fn set_index(&mut self, index: usize, block_id: i32) {
+        let palette_index = match self.palette.iter().position(|&id| id == block_id) {
+            Some(palette_index) => palette_index,
+            None => {
+                self.palette.push(block_id);
+                if self.palette.len() > 1 << self.bits {
+                    self.resize(self.bits + 1);
+                }
+                self.palette.len() - 1
+            }
+        };
+        if self.bits == 0 {
+            return;
+        }
+        let per_word = self.indices_per_word();
+        let shift = (index % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.data[index / per_word];
+        *word = (*word & !mask) | ((palette_index as u64) << shift);
+    }
+
+    /// Repack the indices with another number of bits each.
+    fn resize(&mut self, bits: u32) {
+        let old = Section {
+            palette: self.palette.clone(),
+            bits: self.bits,
+            data: std::mem::take(&mut self.data),
+        };
+        self.bits = bits;
+        self.data =
+            vec![0; (SECTION_VOLUME + self.indices_per_word() - 1) / self.indices_per_word()];
+        if old.bits == 0 {
+            return; // Every index is 0.
+        }
+        let per_word = self.indices_per_word();
+        let old_per_word = old.indices_per_word();
+        let old_mask = (1u64 << old.bits) - 1;
+        for index in 0..SECTION_VOLUME {
+            let shift = (index % old_per_word) as u32 * old.bits;
+            let palette_index = (old.data[index / old_per_word] >> shift) & old_mask;
+            self.data[index / per_word] |= palette_index << ((index % per_word) as u32 * bits);
+        }
+    }
+
+    /// The block at (x, y, z), with y from the bottom of the section.
+    pub fn get(&self, x: usize, y: usize, z: usize) -> i32 {
+        return self.get_index(block_index(x, y, z));
+    }
+
+    /// Set the block at (x, y, z), with y from the bottom of the section.
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block_id: i32) {
+        self.set_index(block_index(x, y, z), block_id);
+    }
+
+    /// The same blocks, with unused blocks dropped from the palette and indices as small as possible.
+    pub fn compacted(&self) -> Section {
+        if self.bits == 0 {
+            return self.clone();
+        }
+        return Section::from_blocks(&self.blocks());
+    }
+
+    /**
+    Write the section: the palette length as a little-endian `u16`, the palette as little-endian `i32`s,
+    then `bits` as a byte, and the data as little-endian `u64`s (none if `bits` is 0).
+    */
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&(self.palette.len() as u16).to_le_bytes())?;
+        for block_id in self.palette.iter() {
+            writer.write_all(&block_id.to_le_bytes())?;
+        }
+        writer.write_all(&[self.bits as u8])?;
+        for word in self.data.iter() {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        return Ok(());
+    }
+
+    /// Read a section written by `write`.
+    pub fn read(mut reader: impl Read) -> io::Result<Section> {
+        let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer[..2])?;
+        let palette_len = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+        if palette_len == 0 || palette_len > SECTION_VOLUME {
+            return Err(invalid_data(format!(
+                "section palette of {} blocks",
+                palette_len
+            )));
+        }
+        let mut palette = Vec::with_capacity(palette_len);
+        for _ in 0..palette_len {
+            reader.read_exact(&mut buffer[..4])?;
+            palette.push(i32::from_le_bytes([
+                buffer[0], buffer[1], buffer[2], buffer[3],
+            ]));
+        }
+        reader.read_exact(&mut buffer[..1])?;
+        let bits = buffer[0] as u32;
+        if bits > 32 || palette_len > 1 << bits {
+            return Err(invalid_data(format!(
+                "section indices of {} bits for {} blocks",
+                bits, palette_len
+            )));
+        }
+        let mut section = Section {
+            palette,
+            bits,
+            data: Vec::new(),
+        };
+        if bits > 0 {
+            let word_count =
+                (SECTION_VOLUME + section.indices_per_word() - 1) / section.indices_per_word();
+            for _ in 0..word_count {
+                reader.read_exact(&mut buffer)?;
+                section.data.push(u64::from_le_bytes(buffer));
+            }
+            // Indices out of the palette would make `get` panic.
+            let mask = (1u64 << bits) - 1;
+            let per_word = section.indices_per_word();
+            for index in 0..SECTION_VOLUME {
+                let shift = (index % per_word) as u32 * bits;
+                if ((section.data[index / per_word] >> shift) & mask) as usize >= palette_len {
+                    return Err(invalid_data("section index out of its palette".to_string()));
+                }
+            }
+        }
+        return Ok(section);
+    }
+}
+
+/**
+The blocks of a chunk, from y = 0 up to its height.
+Blocks are read and written by local coordinates in the chunk with `get` and `set`.
+*/
+#[derive(Clone, Debug)]
+pub struct ChunkBlocks {
+    /// Sections from the bottom.
+    sections: Vec<Section>,
+}
+
+impl ChunkBlocks {
+    /// A chunk of only air. The height must be a multiple of `SECTION_HEIGHT`.
+    pub fn new(height: usize) -> ChunkBlocks {
+        assert!(
+            height % SECTION_HEIGHT == 0,
+            "chunk height {} is not a multiple of {}",
+            height,
+            SECTION_HEIGHT
+        );
+        return ChunkBlocks {
+            sections: vec![Section::single(blocks::AIR); height / SECTION_HEIGHT],
+        };
+    }
+
+    /// Number of blocks in the chunk vertically.
+    pub fn height(&self) -> usize {
+        return self.sections.len() * SECTION_HEIGHT;
+    }
+
+    /// The sections of the chunk from the bottom.
+    pub fn sections(&self) -> &[Section] {
+        return &self.sections;
+    }
+
+    /// A chunk made of sections from the bottom.
+    pub fn from_sections(sections: Vec<Section>) -> ChunkBlocks {
+        return ChunkBlocks { sections };
+    }
+
+    /// The block at (x, y, z). Blocks above the chunk are air.
+    pub fn get(&self, x: usize, y: usize, z: usize) -> i32 {
+        return match self.sections.get(y / SECTION_HEIGHT) {
+            Some(section) => section.get(x, y % SECTION_HEIGHT, z),
+            None => blocks::AIR,
+        };
+    }
+
+    /**
+    Set the block at (x, y, z). Setting a block above the chunk does nothing.
+    Returns whether the block was set.
+    */
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block_id: i32) -> bool {
+        return match self.sections.get_mut(y / SECTION_HEIGHT) {
+            Some(section) => {
+                section.set(x, y % SECTION_HEIGHT, z, block_id);
+                true
+            }
+            None => false,
+        };
+    }
+
+    /**
+    Replace every block that is not air with `f(block, count)`, where `count` is the number of these blocks
+    in a section. `f` is called once for each distinct block of each section, not for every block.
+    */
+    pub fn map_blocks(&mut self, mut f: impl FnMut(i32, usize) -> i32) {
+        for section in self.sections.iter_mut() {
+            let counts = section.palette_counts();
+            for (block_id, count) in section.palette.iter_mut().zip(counts) {
+                if *block_id != blocks::AIR {
+                    *block_id = f(*block_id, count);
+                }
+            }
+        }
+    }
+}
+
+/// Serialized as nested arrays indexed by `[x][y][z]`, as chunks were before sections existed.
+impl Serialize for ChunkBlocks {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let blocks: Vec<Vec<Vec<i32>>> = (0..CHUNK_SIZE)
+            .map(|x| {
+                (0..self.height())
+                    .map(|y| (0..CHUNK_SIZE).map(|z| self.get(x, y, z)).collect())
+                    .collect()
+            })
+            .collect();
+        return blocks.serialize(serializer);
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkBlocks {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let blocks: Vec<Vec<Vec<i32>>> = Vec::deserialize(deserializer)?;
+        let height = blocks.first().map_or(0, |plane| plane.len());
+        if blocks.len() != CHUNK_SIZE || height % SECTION_HEIGHT != 0 {
+            return Err(D::Error::custom(format!(
+                "chunk of {} * {} blocks",
+                blocks.len(),
+                height
+            )));
+        }
+        let mut chunk_blocks = ChunkBlocks::new(height);
+        for (x, plane) in blocks.iter().enumerate() {
+            for (y, row) in plane.iter().enumerate() {
+                if plane.len() != height || row.len() != CHUNK_SIZE {
+                    return Err(D::Error::custom("chunk is not a box of blocks"));
+                }
+                for (z, &block_id) in row.iter().enumerate() {
+                    chunk_blocks.set(x, y, z, block_id);
+                }
+            }
+        }
+        return Ok(chunk_blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks using `count` distinct block IDs, in a scattered order.
+    fn scattered_blocks(count: usize) -> Vec<i32> {
+        return (0..SECTION_VOLUME)
+            .map(|index| ((index * 7919) % count) as i32)
+            .collect();
+    }
+
+    /// Write the section and read it back.
+    fn write_and_read(section: &Section) -> Section {
+        let mut bytes = Vec::new();
+        section.write(&mut bytes).unwrap();
+        let mut reader = &bytes[..];
+        let read = Section::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        return read;
+    }
+
+    #[test]
+    fn single_block_section_round_trips() {
+        let section = Section::single(3);
+        assert_eq!(section.bits, 0);
+        assert!(section.data.is_empty());
+        assert_eq!(section.get(15, 15, 15), 3);
+        assert_eq!(section.palette_counts(), vec![SECTION_VOLUME]);
+
+        let read = write_and_read(&section);
+        assert_eq!(read.bits, 0);
+        assert_eq!(read.blocks(), vec![3; SECTION_VOLUME]);
+        assert!(Section::single(blocks::AIR).is_air());
+        assert!(!read.is_air());
+    }
+
+    #[test]
+    fn sections_of_each_bit_width_round_trip() {
+        for bits in 1..=12 {
+            // One more block than fits in `bits - 1` bits.
+            let count = (1 << (bits - 1)) + 1;
+            let blocks = scattered_blocks(count);
+            let section = Section::from_blocks(&blocks);
+            assert_eq!(section.bits, bits);
+            assert_eq!(section.blocks(), blocks);
+            assert_eq!(section.palette_counts().iter().sum::<usize>(), SECTION_VOLUME);
+
+            let read = write_and_read(&section);
+            assert_eq!(read.bits, bits);
+            assert_eq!(read.blocks(), blocks);
+        }
+    }
+
+    #[test]
+    fn set_resizes_and_keeps_blocks() {
+        let mut section = Section::single(blocks::AIR);
+        let mut expected = vec![blocks::AIR; SECTION_VOLUME];
+        for (block_id, index) in (0..40).zip((0..SECTION_VOLUME).step_by(97)) {
+            section.set_index(index, block_id);
+            expected[index] = block_id;
+            assert_eq!(section.blocks(), expected);
+        }
+        assert_eq!(section.bits, 6);
+
+        // Resizing back down keeps every block while they still fit.
+        section.resize(7);
+        assert_eq!(section.blocks(), expected);
+        let compacted = section.compacted();
+        assert_eq!(compacted.bits, 6);
+        assert_eq!(write_and_read(&compacted).blocks(), expected);
+    }
+
+    #[test]
+    fn compacted_drops_unused_blocks() {
+        let mut section = Section::from_blocks(&scattered_blocks(5));
+        for index in 0..SECTION_VOLUME {
+            section.set_index(index, 1);
+        }
+        let counts = section.palette_counts();
+        assert_eq!(section.palette.len(), 5);
+        for (&block_id, count) in section.palette.iter().zip(counts) {
+            assert_eq!(count, if block_id == 1 { SECTION_VOLUME } else { 0 });
+        }
+        let compacted = section.compacted();
+        assert_eq!(compacted.bits, 0);
+        assert_eq!(compacted.palette, vec![1]);
+    }
+
+    #[test]
+    fn index_out_of_palette_is_rejected() {
+        let section = Section::from_blocks(&scattered_blocks(3));
+        let mut bytes = Vec::new();
+        section.write(&mut bytes).unwrap();
+        // Drop the last block of the palette, so that the indices 2 are out of it.
+        bytes[0] = 2;
+        bytes.drain(10..14);
+        assert!(Section::read(&bytes[..]).is_err());
+    }
+}
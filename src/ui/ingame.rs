@@ -77,10 +77,14 @@ pub fn update_in_game_ui_text(
         With<player::MainPlayer>,
     >,
     query_camera: Query<(&Transform, &GlobalTransform), With<init_game::GameCamera>>,
+    game_map: Res<gamemap::GameMap>,
+    world_clock: Res<daycycle::WorldClock>,
+    weather: Res<weather::Weather>,
 ) {
     let (status_pointer, global_transform) =
         &query_player.get_single().expect("Not exactly one player!");
     let player_status = status_pointer.pointer.lock().unwrap();
+    let biome_sampler = game_map.generator.biome_sampler(game_map.seed);
     let (camera_transform, camera_global_transform) =
         &query_camera.get_single().expect("Not exactly one camera!");
     for mut text in &mut query_uitext {
@@ -89,12 +93,25 @@ pub fn update_in_game_ui_text(
 Player rotation (around Y-axis): {:.4} degrees
 Player velocity: {}
 Camera position: {}
-Camera rotation (vertical, around X-axis): {:.4} degrees",
+Camera rotation (vertical, around X-axis): {:.4} degrees
+World seed: {}
+Biome: {}
+Time: day {}, {:.0}% ({})
+Weather: {:?}",
             player_status.position,
             player_status.rotation * 180. / PI,
             player_status.velocity,
             camera_global_transform.translation(),
-            camera_transform.rotation.to_euler(EulerRot::XYZ).0 * 180. / PI
+            camera_transform.rotation.to_euler(EulerRot::XYZ).0 * 180. / PI,
+            game_map.seed,
+            biome_sampler
+                .biome_at(player_status.position.x.floor() as i32, player_status.position.z.floor() as i32)
+                .properties()
+                .name,
+            world_clock.day() + 1,
+            world_clock.time_of_day() * 100.,
+            if world_clock.is_night() { "night" } else { "day" },
+            weather.kind
         );
     }
 }
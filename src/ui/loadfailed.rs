@@ -0,0 +1,199 @@
+use crate::*;
+use bevy::prelude::*;
+use std::path::Path;
+
+use super::*;
+
+// Below are the group identifiers of the buttons, texts, etc.
+/// A "tag" component for a section of the load failed UI.
+#[derive(Component)]
+pub(crate) struct LoadFailedUI;
+/// A "name" for the text showing why the world failed to load.
+#[derive(Component)]
+pub(crate) struct LoadFailedUIMessage;
+/// A "name" for the retry button on the load failed page.
+#[derive(Component)]
+pub(crate) struct LoadFailedUIRetryButton;
+/// A "name" for the open backup button on the load failed page.
+#[derive(Component)]
+pub(crate) struct LoadFailedUIOpenBackupButton;
+/// A "name" for the back button on the load failed page.
+#[derive(Component)]
+pub(crate) struct LoadFailedUIBackButton;
+
+
+// Below are the behaviors when state changes.
+/**
+Initialize the UI camera and the load failed UI, showing why the world failed to load.
+ */
+pub(crate) fn init_load_failed(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    world_name: Res<gamemap::WorldName>,
+    load_failure: Res<gamemap::WorldLoadFailure>,
+) {
+    commands.spawn((LoadFailedUI, UICamera, Camera2dBundle { ..default() }));
+    // Initialize the texts.
+    commands.spawn((
+        LoadFailedUI,
+        TextBundle::from_section(
+            "World failed to load",
+            TextStyle {
+                font: asset_server.load("fonts/指尖隶书体.ttf"),
+                font_size: 100.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_text_alignment(TextAlignment::Center)
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Percent(20.),
+                top: Val::Percent(20.),
+                ..default()
+            },
+            ..default()
+        }),
+    ));
+    let message = match &load_failure.error {
+        Some(err) => format!("{}: {}", world_name.name.as_ref().unwrap(), err),
+        None => String::new(),
+    };
+    commands.spawn((
+        LoadFailedUI,
+        LoadFailedUIMessage,
+        TextBundle::from_section(
+            message,
+            TextStyle {
+                font: asset_server.load("fonts/指尖隶书体.ttf"),
+                font_size: 30.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Percent(20.),
+                top: Val::Percent(40.),
+                ..default()
+            },
+            max_size: Size::new(Val::Percent(60.), Val::Undefined),
+            ..default()
+        }),
+    ));
+    // Initialize the buttons with children texts.
+    spawn_load_failed_button(&mut commands, &asset_server, LoadFailedUIRetryButton, "Retry", 30.);
+    spawn_load_failed_button(&mut commands, &asset_server, LoadFailedUIOpenBackupButton, "Open Backup", 20.);
+    spawn_load_failed_button(&mut commands, &asset_server, LoadFailedUIBackButton, "Back", 10.);
+}
+
+/// Spawn a button of the load failed UI, at `bottom` percent from the bottom of the window.
+fn spawn_load_failed_button(
+    commands: &mut Commands,
+    asset_server: &Res<AssetServer>,
+    button_name: impl Component,
+    text: &str,
+    bottom: f32,
+) {
+    commands
+        .spawn((
+            LoadFailedUI,
+            button_name,
+            ButtonBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        bottom: Val::Percent(bottom),
+                        right: Val::Percent(50.),
+                        ..default()
+                    },
+                    ..default()
+                },
+                background_color: BackgroundColor(Color::BLACK),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                text,
+                TextStyle {
+                    font: asset_server.load("fonts/指尖隶书体.ttf"),
+                    font_size: 50.0,
+                    color: Color::WHITE,
+                },
+            ));
+        });
+}
+
+/**
+Clears the load failed UI and its camera.
+ */
+pub(crate) fn clear_load_failed(mut commands: Commands, query_ui: Query<Entity, With<LoadFailedUI>>) {
+    for ui in &query_ui {
+        commands.entity(ui).despawn_recursive();
+    }
+}
+
+
+// Below is how to react to clicks.
+/**
+   Reaction for Retry button: load the world again.
+*/
+pub(crate) fn load_failed_retry_button_reaction(
+    interaction_query: Query<&Interaction, With<LoadFailedUIRetryButton>>,
+    mut game_state: ResMut<NextState<GameState>>,
+) {
+    for interaction in &interaction_query {
+        if *interaction == Interaction::Clicked {
+            game_state.set(GameState::Loading);
+        }
+    }
+}
+/**
+   Reaction for Open Backup button: replace the world with its latest backup, and load it.
+   The replaced world is kept in the backups folder.
+*/
+pub(crate) fn load_failed_open_backup_button_reaction(
+    interaction_query: Query<&Interaction, With<LoadFailedUIOpenBackupButton>>,
+    mut game_state: ResMut<NextState<GameState>>,
+    mut query_message: Query<&mut Text, With<LoadFailedUIMessage>>,
+    world_name: Res<gamemap::WorldName>,
+) {
+    for interaction in &interaction_query {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        let world_name = world_name.name.as_ref().unwrap();
+        let saves_dir = Path::new(region::SAVES_DIR);
+        let result = match region::list_backups(saves_dir, world_name) {
+            Ok(backups) => match backups.first() {
+                Some(backup_dir) => region::restore_backup(saves_dir, world_name, backup_dir)
+                    .map_err(|err| format!("Failed to open backup {:?}: {}", backup_dir, err)),
+                None => Err(format!("{} has no backup", world_name)),
+            },
+            Err(err) => Err(format!("Failed to find backups: {}", err)),
+        };
+        match result {
+            Ok(()) => game_state.set(GameState::Loading),
+            Err(message) => {
+                println!("{}", message);
+                for mut text in &mut query_message {
+                    text.sections[0].value = message.clone();
+                }
+            }
+        }
+    }
+}
+/**
+   Reaction for Back button: go back to the main menu without touching the world.
+*/
+pub(crate) fn load_failed_back_button_reaction(
+    interaction_query: Query<&Interaction, With<LoadFailedUIBackButton>>,
+    mut game_state: ResMut<NextState<GameState>>,
+) {
+    for interaction in &interaction_query {
+        if *interaction == Interaction::Clicked {
+            game_state.set(GameState::MainMenu);
+        }
+    }
+}
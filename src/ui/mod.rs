@@ -9,6 +9,9 @@
 //! At `InGame` state, there is in-game UI.
 //! It does not have states. Instead, all components are controlled by its own bool value: show or not.
 //!
+//! At `LoadFailed` state, there is a page showing why the world failed to load,
+//! with buttons to retry, open a backup, or go back to the main menu.
+//!
 //! At `Pause` state, there is pause UI.
 //! It has states. To be determined...
 //! TODO: Implement the pause UI.
@@ -22,12 +25,14 @@ use std::{
 
 pub mod chooseworld;
 pub mod ingame;
+pub mod loadfailed;
 pub mod mainmenu;
 pub mod pause;
 pub mod setting;
 use mainmenu::*;
 use chooseworld::*;
 use ingame::*;
+use loadfailed::*;
 use setting::*;
 use pause::*;
 
@@ -63,6 +68,17 @@ impl Plugin for UIPlugin {
             main_menu_index_choose_world_button3_reaction.in_set(OnUpdate(MainMenuUIState::ChooseWorld)),
         ));
 
+        // Load failed UI.
+        app.add_systems((
+            init_load_failed.in_schedule(OnEnter(GameState::LoadFailed)),
+            clear_load_failed.in_schedule(OnExit(GameState::LoadFailed)),
+        ));
+        app.add_systems((
+            load_failed_retry_button_reaction.in_set(OnUpdate(GameState::LoadFailed)),
+            load_failed_open_backup_button_reaction.in_set(OnUpdate(GameState::LoadFailed)),
+            load_failed_back_button_reaction.in_set(OnUpdate(GameState::LoadFailed)),
+        ));
+
         // In-game UI.
         app.add_state::<InGameUIState>();
         app.add_system(init_in_game_ui_text.in_schedule(OnEnter(GameState::InGame)));
@@ -208,18 +208,20 @@ pub(crate) fn pause_index_return_button_reaction(
 pub(crate) fn pause_index_main_menu_button_reaction(
     mut interaction_query: Query<&Interaction, With<PauseIndexUIMainmenuButton>>,
     mut game_state: ResMut<NextState<GameState>>,
-    gamemap: Res<gamemap::GameMap>,
+    mut gamemap: ResMut<gamemap::GameMap>,
     mut commands: Commands,
     world_name: Res<gamemap::WorldName>,
+    block_registry: Res<blocks::BlockRegistry>,
     query_game_entities: Query<Entity, With<entities::Entity>>,
-    query_game_blocks: Query<Entity, With<blocks::Block>>,
+    query_game_blocks: Query<Entity, With<chunk_mesh::ChunkMesh>>,
     query_game_camera: Query<Entity, With<init_game::GameCamera>>,
     query_game_lights: Query<Entity, With<DirectionalLight>>,
+    query_precipitation: Query<Entity, With<weather::PrecipitationParticle>>,
 ) {
     for interaction in &mut interaction_query {
         match *interaction {
             Interaction::Clicked => {
-                match gamemap::save_gamemap(&gamemap, &world_name) {
+                match gamemap::save_gamemap(&mut gamemap, &world_name, &block_registry) {
                     Ok(_) => {
                         println!("Saved world to {}", &world_name.name.clone().unwrap());
                         // Despawn everything in the game.
@@ -235,8 +237,15 @@ pub(crate) fn pause_index_main_menu_button_reaction(
                         for entity in &query_game_lights {
                             commands.entity(entity).despawn_recursive();
                         }
+                        for entity in &query_precipitation {
+                            commands.entity(entity).despawn_recursive();
+                        }
+                    }
+                    // Stay in the pause screen, so that the player can try again or keep playing.
+                    Err(err) => {
+                        println!("Save failed: {}", err);
+                        continue;
                     }
-                    e => panic!("Save gamemap failed: {:?}", e),
                 }
                 game_state.set(GameState::MainMenu);
             }
@@ -0,0 +1,147 @@
+//! Vegetation
+//! ---
+//! Plants change slowly in random ticks (see the `block_updates` module):
+//! - Grass spreads from grass blocks with enough light above them to dirt nearby that is lit and uncovered,
+//!   and dies back to dirt when covered by an opaque block or a fluid.
+//! - Leaves decay when no log is within `LEAVES_REACH` steps through other leaves, e.g. after the trunk is removed.
+//! - Saplings on dirt or grass grow into trees, the same trees as in world generation (see `decoration::grow_tree`),
+//!   if they have enough light and room for the trunk.
+
+use crate::block_updates::{add, BlockWorld};
+use crate::*;
+use rand::Rng;
+use std::collections::{HashSet, VecDeque};
+
+/// The light above a grass block needed to spread grass.
+const GRASS_SPREAD_LIGHT: u8 = 9;
+/// The light above dirt needed for grass to spread onto it.
+const GRASS_GROW_LIGHT: u8 = 4;
+/// Leaves farther than this many steps from a log, through other leaves, decay.
+const LEAVES_REACH: usize = 6;
+/// The light a sapling needs to grow.
+const SAPLING_GROW_LIGHT: u8 = 9;
+/// The chance that a sapling grows in each random tick it gets.
+const SAPLING_GROW_CHANCE: f32 = 1. / 7.;
+
+const UP: (i32, i32, i32) = (0, 1, 0);
+const DOWN: (i32, i32, i32) = (0, -1, 0);
+
+/// Whether the block covers the block below it from the sky: an opaque block or a fluid.
+fn covers(world: &BlockWorld, block_id: i32) -> bool {
+    return !world.block_registry.is_transparent(block_id)
+        || world.block_registry.fluid(block_id).is_some();
+}
+
+/// Whether the block is of a kind of blocks whose string ID ends with the suffix, e.g. `"_log"`.
+fn has_suffix(world: &BlockWorld, block_id: i32, suffix: &str) -> bool {
+    return world
+        .block_registry
+        .get(block_id)
+        .map_or(false, |block| block.name.ends_with(suffix));
+}
+
+/// Random tick of grass blocks: die back when covered, or spread to dirt nearby.
+pub fn grow_grass(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    let dirt = world.block_registry.id("dirt").unwrap();
+    let grass_block = world.block_registry.id("grass_block").unwrap();
+    let above = add(position, UP);
+    // Above the world is open sky.
+    if covers(world, world.block(above).unwrap_or(blocks::AIR)) {
+        world.set(position, dirt);
+        return;
+    }
+    if world.light(above) < GRASS_SPREAD_LIGHT {
+        return;
+    }
+    let mut rng = rand::thread_rng();
+    let target = add(
+        position,
+        (
+            rng.gen_range(-1, 2),
+            rng.gen_range(-3, 2),
+            rng.gen_range(-1, 2),
+        ),
+    );
+    if world.block(target) != Some(dirt) {
+        return;
+    }
+    let target_above = add(target, UP);
+    if covers(world, world.block(target_above).unwrap_or(blocks::AIR))
+        || world.light(target_above) < GRASS_GROW_LIGHT
+    {
+        return;
+    }
+    world.set(target, grass_block);
+}
+
+/// Random tick of leaves: decay if no log is within reach. Leaves next to unloaded chunks are kept.
+pub fn decay_leaves(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    let mut visited = HashSet::from([position]);
+    let mut queue = VecDeque::from([(position, 0)]);
+    while let Some((leaves, distance)) = queue.pop_front() {
+        for offset in block_updates::NEIGHBORS {
+            let neighbor = add(leaves, offset);
+            let block_id = match world.block(neighbor) {
+                Some(block_id) => block_id,
+                None if neighbor.1 < 0 || neighbor.1 >= world.game_map.height as i32 => continue,
+                None => return, // An unloaded chunk, which may have a log.
+            };
+            if has_suffix(world, block_id, "_log") {
+                return;
+            }
+            if distance + 1 < LEAVES_REACH
+                && has_suffix(world, block_id, "_leaves")
+                && visited.insert(neighbor)
+            {
+                queue.push_back((neighbor, distance + 1));
+            }
+        }
+    }
+    world.set(position, blocks::AIR);
+}
+
+/// Grow trees in loaded chunks. Blocks in unloaded chunks are dropped.
+impl<'a> decoration::TreeWriter for BlockWorld<'a> {
+    fn set(&mut self, position: (i32, i32, i32), block_id: i32, only_replace_air: bool) {
+        if !only_replace_air || self.block(position) == Some(blocks::AIR) {
+            BlockWorld::set(self, position, block_id);
+        }
+    }
+}
+
+/// Random tick of saplings: sometimes grow into a tree, if there is enough light and room.
+pub fn grow_sapling(world: &mut BlockWorld, position: (i32, i32, i32)) {
+    let mut rng = rand::thread_rng();
+    if rng.gen::<f32>() >= SAPLING_GROW_CHANCE || world.light(position) < SAPLING_GROW_LIGHT {
+        return;
+    }
+    let sapling = world.block(position).unwrap_or(blocks::AIR);
+    let species = match world
+        .block_registry
+        .get(sapling)
+        .map(|block| block.name.as_str())
+    {
+        Some("oak_sapling") => decoration::TreeSpecies::Oak,
+        Some("birch_sapling") => decoration::TreeSpecies::Birch,
+        Some("spruce_sapling") => decoration::TreeSpecies::Spruce,
+        _ => return,
+    };
+    let ground = world.block(add(position, DOWN)).unwrap_or(blocks::AIR);
+    if !["dirt", "grass_block", "snowy_grass_block"]
+        .iter()
+        .any(|&name| world.block_registry.id(name) == Some(ground))
+    {
+        return;
+    }
+    let height = decoration::trunk_height(species, rng.gen_range(0, 3));
+    // Room for the trunk, and the leaves on top of it.
+    let has_room = (1..=height + 1).all(|dy| match world.block(add(position, (0, dy, 0))) {
+        Some(block_id) => block_id == blocks::AIR || has_suffix(world, block_id, "_leaves"),
+        None => false,
+    });
+    if !has_room {
+        return;
+    }
+    let block_registry = world.block_registry;
+    decoration::grow_tree(world, species, position, height, block_registry);
+}
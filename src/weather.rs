@@ -0,0 +1,370 @@
+//! Weather
+//! ---
+//! The weather of the world is clear, rain, snow or a thunderstorm. It lasts a random time, then changes,
+//! and is saved with the world.
+//!
+//! Rain and thunderstorms fall as snow in cold places: in cold biomes, and high in the mountains
+//! (see `BiomeProperties::snow_line`). Snow falls everywhere when it snows, but only stays on the ground
+//! in those cold places, where grass becomes snowy grass and snow blocks pile up one block high.
+//!
+//! Precipitation is rendered as particles falling around the camera.
+//! Clouds dim the sun and the sky light (see `daycycle::sky_darkening`), and thunderstorms flash lightning.
+
+use crate::*;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Plugin responsible for changing the weather, rendering precipitation, and piling up snow.
+pub struct WeatherPlugin;
+impl Plugin for WeatherPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Weather>();
+        app.init_resource::<WeatherAssets>();
+        app.add_system(
+            update_weather
+                .before(daycycle::update_sky)
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+        app.add_system(
+            flash_lightning
+                .after(daycycle::update_sky)
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+        app.add_systems(
+            (update_precipitation, accumulate_snow)
+                .before(chunk_mesh::rebuild_dirty_chunk_meshes)
+                .in_set(OnUpdate(GameState::InGame)),
+        );
+    }
+}
+
+/// Particles spawn at most this far (in blocks) from the camera horizontally.
+const PRECIPITATION_RADIUS: f32 = 16.;
+/// Particles spawn this high (in blocks) above the camera, and disappear as far below it.
+const PRECIPITATION_HEIGHT: f32 = 16.;
+/// At most this many particles exist at once.
+const MAX_PARTICLES: usize = 3000;
+const RAIN_SPEED: f32 = 14.;
+const SNOW_SPEED: f32 = 2.;
+/// Chance per second of snow piling up on a random column of each loaded chunk, when it is snowing there.
+const SNOW_PER_CHUNK_PER_SECOND: f32 = 0.5;
+/// Average seconds between lightning flashes in a thunderstorm, and how long a flash lasts.
+const LIGHTNING_INTERVAL: f32 = 12.;
+const LIGHTNING_DURATION: f32 = 0.15;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeatherKind {
+    #[default]
+    Clear,
+    Rain,
+    Snow,
+    Thunder,
+}
+
+/// What falls from the sky at a place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precipitation {
+    Rain,
+    Snow,
+}
+
+/**
+The weather of the world. This is a global resource, set from `GameMap::weather` when a world is loaded,
+and copied back into it as it changes, so that it is saved with the world.
+*/
+#[derive(Resource, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Weather {
+    pub kind: WeatherKind,
+    /// Seconds of world time until the weather changes.
+    pub remaining: f64,
+}
+
+impl Weather {
+    /// Whether anything falls from the sky.
+    pub fn is_precipitating(&self) -> bool {
+        return self.kind != WeatherKind::Clear;
+    }
+
+    /// What falls from the sky at height `y` in a biome, if anything.
+    pub fn precipitation_at(&self, biome: biomes::Biome, y: i32) -> Option<Precipitation> {
+        return match self.kind {
+            WeatherKind::Clear => None,
+            WeatherKind::Snow => Some(Precipitation::Snow),
+            WeatherKind::Rain | WeatherKind::Thunder => {
+                match y >= 0 && y as usize >= biome.properties().snow_line {
+                    true => Some(Precipitation::Snow),
+                    false => Some(Precipitation::Rain),
+                }
+            }
+        };
+    }
+
+    /// How cloudy the sky is in 0..1. Clouds dim the sun and gray the sky.
+    pub fn cloudiness(&self) -> f32 {
+        return match self.kind {
+            WeatherKind::Clear => 0.,
+            WeatherKind::Rain | WeatherKind::Snow => 0.6,
+            WeatherKind::Thunder => 0.9,
+        };
+    }
+
+    /// How many levels the clouds dim sky light by.
+    pub fn sky_darkening(&self) -> u8 {
+        return match self.kind {
+            WeatherKind::Clear => 0,
+            WeatherKind::Rain | WeatherKind::Snow => 2,
+            WeatherKind::Thunder => 4,
+        };
+    }
+
+    /// Change to the next weather: clear weather after rain or snow, and any weather after clear weather.
+    fn change(&mut self, rng: &mut impl Rng) {
+        *self = match self.kind {
+            WeatherKind::Clear => {
+                let kind = match rng.gen_range(0, 4) {
+                    0 | 1 => WeatherKind::Rain,
+                    2 => WeatherKind::Snow,
+                    _ => WeatherKind::Thunder,
+                };
+                Weather {
+                    kind,
+                    remaining: rng.gen_range(0.2, 0.6) * daycycle::DAY_LENGTH,
+                }
+            }
+            _ => Weather {
+                kind: WeatherKind::Clear,
+                remaining: rng.gen_range(0.5, 2.) * daycycle::DAY_LENGTH,
+            },
+        };
+    }
+}
+
+/// Meshes and materials of precipitation particles.
+#[derive(Resource)]
+pub struct WeatherAssets {
+    rain_mesh: Handle<Mesh>,
+    rain_material: Handle<StandardMaterial>,
+    snow_mesh: Handle<Mesh>,
+    snow_material: Handle<StandardMaterial>,
+}
+
+impl FromWorld for WeatherAssets {
+    fn from_world(world: &mut World) -> Self {
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        let rain_mesh = meshes.add(Mesh::from(shape::Box::new(0.02, 0.5, 0.02)));
+        let snow_mesh = meshes.add(Mesh::from(shape::Cube { size: 0.08 }));
+        let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+        let rain_material = materials.add(StandardMaterial {
+            base_color: Color::rgba(0.5, 0.6, 0.9, 0.6),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..default()
+        });
+        let snow_material = materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            unlit: true,
+            ..default()
+        });
+        return WeatherAssets {
+            rain_mesh,
+            rain_material,
+            snow_mesh,
+            snow_material,
+        };
+    }
+}
+
+/// A component for a falling rain drop or snowflake.
+#[derive(Component)]
+pub struct PrecipitationParticle {
+    velocity: Vec3,
+}
+
+/// Change the weather when it has lasted long enough, and keep it in the game map so that it is saved with the world.
+fn update_weather(
+    time: Res<Time>,
+    mut weather: ResMut<Weather>,
+    mut game_map: ResMut<gamemap::GameMap>,
+) {
+    weather.remaining -= time.delta_seconds_f64();
+    if weather.remaining <= 0. {
+        weather.change(&mut rand::thread_rng());
+        println!("The weather changes to {:?}", weather.kind);
+    }
+    game_map.weather = *weather;
+}
+
+/**
+Spawn precipitation particles above the camera, move them down,
+and despawn them when they hit a solid block or fall far below the camera.
+*/
+fn update_precipitation(
+    mut commands: Commands,
+    time: Res<Time>,
+    weather: Res<Weather>,
+    weather_assets: Res<WeatherAssets>,
+    game_map: Res<gamemap::GameMap>,
+    block_registry: Res<blocks::BlockRegistry>,
+    query_camera: Query<&GlobalTransform, With<init_game::GameCamera>>,
+    mut query_particles: Query<(Entity, &mut Transform, &PrecipitationParticle)>,
+) {
+    let camera_position = match query_camera.get_single() {
+        Ok(global_transform) => global_transform.translation(),
+        Err(_) => return,
+    };
+    let delta = time.delta_seconds();
+    let mut particle_count = 0;
+    for (entity, mut transform, particle) in query_particles.iter_mut() {
+        transform.translation += particle.velocity * delta;
+        let hit_block = match game_map.query_block_by_xyz(transform.translation) {
+            Some(block_id) => block_registry.is_solid(block_id),
+            None => false,
+        };
+        if hit_block || transform.translation.y < camera_position.y - PRECIPITATION_HEIGHT {
+            commands.entity(entity).despawn();
+        } else {
+            particle_count += 1;
+        }
+    }
+
+    let rate = match weather.kind {
+        WeatherKind::Clear => return,
+        WeatherKind::Rain => 600.,
+        WeatherKind::Snow => 250.,
+        WeatherKind::Thunder => 900.,
+    };
+    let mut rng = rand::thread_rng();
+    let biome_sampler = game_map.generator.biome_sampler(game_map.seed);
+    // Spawn `rate * delta` particles on average.
+    let expected = rate * delta;
+    let mut spawn_count = expected as usize + (rng.gen::<f32>() < expected.fract()) as usize;
+    spawn_count = spawn_count.min(MAX_PARTICLES.saturating_sub(particle_count));
+    for _ in 0..spawn_count {
+        let position = camera_position
+            + Vec3::new(
+                rng.gen_range(-PRECIPITATION_RADIUS, PRECIPITATION_RADIUS),
+                PRECIPITATION_HEIGHT,
+                rng.gen_range(-PRECIPITATION_RADIUS, PRECIPITATION_RADIUS),
+            );
+        let biome = biome_sampler.biome_at(position.x.floor() as i32, position.z.floor() as i32);
+        let (mesh, material, velocity) =
+            match weather.precipitation_at(biome, position.y.floor() as i32) {
+                Some(Precipitation::Rain) => (
+                    weather_assets.rain_mesh.clone(),
+                    weather_assets.rain_material.clone(),
+                    Vec3::new(0., -RAIN_SPEED, 0.),
+                ),
+                Some(Precipitation::Snow) => (
+                    weather_assets.snow_mesh.clone(),
+                    weather_assets.snow_material.clone(),
+                    Vec3::new(
+                        rng.gen_range(-0.3, 0.3),
+                        -SNOW_SPEED,
+                        rng.gen_range(-0.3, 0.3),
+                    ),
+                ),
+                None => continue,
+            };
+        commands.spawn((
+            PrecipitationParticle { velocity },
+            PbrBundle {
+                mesh,
+                material,
+                transform: Transform::from_translation(position),
+                ..default()
+            },
+        ));
+    }
+}
+
+/**
+Pile up snow on random columns of loaded chunks where it is snowing and cold enough for snow to stay:
+grass at the surface becomes snowy grass, and other full blocks get a snow block on top.
+Snow never piles up on snow, transparent blocks or plants.
+*/
+fn accumulate_snow(
+    time: Res<Time>,
+    weather: Res<Weather>,
+    game_map: Res<gamemap::GameMap>,
+    mut chunk_meshes: ResMut<chunk_mesh::ChunkMeshes>,
+    mut light_map: ResMut<lighting::LightMap>,
+    mut block_updates: ResMut<block_updates::BlockUpdates>,
+    block_registry: Res<blocks::BlockRegistry>,
+) {
+    if !weather.is_precipitating() {
+        return;
+    }
+    let grass_block = block_registry.id("grass_block").unwrap();
+    let snowy_grass_block = block_registry.id("snowy_grass_block").unwrap();
+    let snow_block = block_registry.id("snow_block").unwrap();
+    let size = gamemap::CHUNK_SIZE as i32;
+    let chance = SNOW_PER_CHUNK_PER_SECOND * time.delta_seconds();
+    let biome_sampler = game_map.generator.biome_sampler(game_map.seed);
+    let mut rng = rand::thread_rng();
+    let mut world = block_updates::BlockWorld {
+        game_map: &game_map,
+        block_registry: &block_registry,
+        light_map: &mut light_map,
+        chunk_meshes: &mut chunk_meshes,
+        block_updates: &mut block_updates,
+    };
+    for (&chunk_key, chunk) in game_map.map.iter() {
+        if rng.gen::<f32>() >= chance {
+            continue;
+        }
+        let x = rng.gen_range(0, gamemap::CHUNK_SIZE);
+        let z = rng.gen_range(0, gamemap::CHUNK_SIZE);
+        let (world_x, world_z) = (chunk_key.0 * size + x as i32, chunk_key.1 * size + z as i32);
+        let biome = biome_sampler.biome_at(world_x, world_z);
+        let (y, block_id) = {
+            let chunk_blocks = chunk.blocks.lock().unwrap();
+            let top = match (0..chunk_blocks.height())
+                .rev()
+                .find(|&y| chunk_blocks.get(x, y, z) != blocks::AIR)
+            {
+                Some(top) => top,
+                None => continue,
+            };
+            // Snow stays only where it is cold enough for rain to fall as snow.
+            if top + 1 < biome.properties().snow_line {
+                continue;
+            }
+            let ground = chunk_blocks.get(x, top, z);
+            if ground == grass_block {
+                (top, snowy_grass_block)
+            } else if ground == snow_block
+                || ground == snowy_grass_block
+                || !block_registry.is_solid(ground)
+                || block_registry.is_transparent(ground)
+            {
+                continue;
+            } else {
+                (top + 1, snow_block)
+            }
+        };
+        // Nothing is set at the top of the world.
+        world.set((world_x, y as i32, world_z), block_id);
+    }
+}
+
+/// Flash the sky and the ambient light now and then in thunderstorms.
+fn flash_lightning(
+    time: Res<Time>,
+    weather: Res<Weather>,
+    mut clear_color: ResMut<ClearColor>,
+    mut ambient_light: ResMut<AmbientLight>,
+    mut flash_remaining: Local<f32>,
+) {
+    let delta = time.delta_seconds();
+    if *flash_remaining > 0. {
+        *flash_remaining -= delta;
+        clear_color.0 = Color::rgb(0.85, 0.85, 0.95);
+        ambient_light.brightness = 1.;
+    } else if weather.kind == WeatherKind::Thunder
+        && rand::thread_rng().gen::<f32>() < delta / LIGHTNING_INTERVAL
+    {
+        *flash_remaining = LIGHTNING_DURATION;
+    }
+}
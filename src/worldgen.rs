@@ -0,0 +1,410 @@
+//! World generation
+//! ---
+//! A world generator produces the blocks of new chunks. `GameMap` creates its generator from
+//! `GeneratorSettings`, which is chosen when a world is created and saved in `level.json`.
+//!
+//! Built-in generators:
+//! - `NoiseGenerator`: the normal terrain, with biomes, caves, ores, trees and plants.
+//! - `SuperflatGenerator`: flat layers of blocks, given by a preset string like `bedrock,2*dirt,grass_block`.
+//! - `VoidGenerator`: nothing but a small platform to stand on.
+//! - Single biome: the normal terrain, but with one biome everywhere.
+
+use crate::*;
+use gamemap::CHUNK_SIZE;
+use noise::{NoiseFn, Perlin};
+use sections::ChunkBlocks;
+use serde::{Deserialize, Serialize};
+
+/// Something that generates the blocks of new chunks.
+/// It must be deterministic: the same chunk always gets the same blocks in the same world.
+pub trait WorldGenerator {
+    /// Returns the blocks of the chunk at the chunk coordinates, as high as the world.
+    fn generate_blocks(&self, chunk_key: (i32, i32)) -> ChunkBlocks;
+}
+
+/**
+Which world generator a world uses, with its options. Saved in `level.json`.
+Worlds saved before generators were selectable use `Noise`.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GeneratorSettings {
+    #[default]
+    Noise,
+    Superflat {
+        /// The layers from bottom to top, see `SuperflatGenerator::parse_layers`.
+        layers: String,
+    },
+    Void,
+    SingleBiome {
+        biome: biomes::Biome,
+    },
+}
+
+/// The layers of a superflat world if none are given.
+pub const DEFAULT_SUPERFLAT_LAYERS: &str = "bedrock,2*dirt,grass_block";
+
+impl GeneratorSettings {
+    /**
+    Parse generator settings from text, e.g. given on the command line:
+    `noise`, `superflat`, `superflat:<layers>`, `void`, or `single_biome:<biome name>`.
+    Superflat layers are checked against the block registry.
+    */
+    pub fn parse(
+        text: &str,
+        block_registry: &blocks::BlockRegistry,
+    ) -> Result<GeneratorSettings, String> {
+        let (name, options) = match text.split_once(':') {
+            Some((name, options)) => (name, Some(options)),
+            None => (text, None),
+        };
+        return match (name, options) {
+            ("noise", None) => Ok(GeneratorSettings::Noise),
+            ("superflat", layers) => {
+                let layers = layers.unwrap_or(DEFAULT_SUPERFLAT_LAYERS).to_string();
+                SuperflatGenerator::parse_layers(&layers, block_registry, false)?;
+                Ok(GeneratorSettings::Superflat { layers })
+            }
+            ("void", None) => Ok(GeneratorSettings::Void),
+            ("single_biome", Some(biome_name)) => match biomes::Biome::from_name(biome_name) {
+                Some(biome) => Ok(GeneratorSettings::SingleBiome { biome }),
+                None => Err(format!("Unknown biome \"{}\"", biome_name)),
+            },
+            _ => Err(format!("Unknown world generator \"{}\"", text)),
+        };
+    }
+
+    /**
+    Check the settings of an existing world, loaded from its save.
+    Superflat layers of blocks removed since the world was created are air, but the layers must still be valid.
+    */
+    pub fn check(&self, block_registry: &blocks::BlockRegistry) -> Result<(), String> {
+        if let GeneratorSettings::Superflat { layers } = self {
+            SuperflatGenerator::parse_layers(layers, block_registry, true)?;
+        }
+        return Ok(());
+    }
+
+    /// The biome sampler of a world generated with these settings.
+    pub fn biome_sampler(&self, seed: u64) -> biomes::BiomeSampler {
+        return match self {
+            GeneratorSettings::SingleBiome { biome } => biomes::BiomeSampler::single(*biome),
+            _ => biomes::BiomeSampler::new(seed),
+        };
+    }
+
+    /// Create the world generator of a world of the height.
+    pub fn create<'a>(
+        &self,
+        seed: u64,
+        terrain_seeds: [u32; 3],
+        height: usize,
+        block_registry: &'a blocks::BlockRegistry,
+    ) -> Box<dyn WorldGenerator + 'a> {
+        return match self {
+            // A single biome world is generated like the default one, with its biome everywhere.
+            GeneratorSettings::Noise | GeneratorSettings::SingleBiome { .. } => {
+                Box::new(NoiseGenerator {
+                    terrain: TerrainGenerator::new(
+                        seed,
+                        terrain_seeds,
+                        self.biome_sampler(seed),
+                        height,
+                        block_registry,
+                    ),
+                })
+            }
+            GeneratorSettings::Superflat { layers } => Box::new(SuperflatGenerator {
+                // Layers were checked when the world was created or loaded (see `check`).
+                layers: SuperflatGenerator::parse_layers(layers, block_registry, true).unwrap(),
+                height,
+            }),
+            GeneratorSettings::Void => Box::new(VoidGenerator {
+                platform: block_registry.id("stone").unwrap(),
+                height,
+            }),
+        };
+    }
+}
+
+/// Horizontal size (in blocks) of the largest terrain features.
+const TERRAIN_SCALE: f64 = 96.0;
+/// Scale (in blocks) of the 3D noise that makes overhangs.
+const OVERHANG_SCALE: f64 = 24.0;
+/// How far (in blocks) overhangs may reach above or below the terrain height.
+const OVERHANG_STRENGTH: f64 = 4.0;
+/// Scale (in blocks) of the 3D noises that carve caves.
+const CAVE_SCALE: f64 = 32.0;
+/// Caves are carved where both cave noises are within this distance from 0. Larger means wider caves.
+const CAVE_THRESHOLD: f64 = 0.08;
+/// Scale (in blocks) of the 2D noise that carves ravines.
+const RAVINE_SCALE: f64 = 128.0;
+/// Ravines are carved where the ravine noise is within this distance from 0. Larger means wider ravines.
+const RAVINE_THRESHOLD: f64 = 0.015;
+/// Air open to the sky is filled with water up to this height, making lakes in low places.
+const SEA_LEVEL: usize = 12;
+/// Caves are filled with lava up to this height.
+const LAVA_LEVEL: usize = 8;
+
+/**
+The noises and blocks that make the terrain of a world, created from the world seed.
+Every column is generated independently, so any column of any chunk can be generated on its own,
+e.g. to find the ground under a tree growing from a neighbor chunk.
+
+Use Berlin Noise with different freqencies and amplitude to show different terrains.
+The noise is sampled in absolute world coordinates, so terrain is continuous across chunks.
+The base height, hilliness and surface blocks of each column come from its biome (see the `biomes` module).
+The terrain height is disturbed by 3D noise to make overhangs,
+then caves (where two 3D noises are both near 0) and ravines (where a 2D noise is near 0) are carved out.
+Finally, low caves are filled with lava, and low places open to the sky with water.
+*/
+pub struct TerrainGenerator<'a> {
+    pub seed: u64,
+    pub biome_sampler: biomes::BiomeSampler,
+    pub block_registry: &'a blocks::BlockRegistry,
+    /// The height of the world.
+    pub height: usize,
+    noise1: Perlin,
+    noise2: Perlin,
+    noise3: Perlin,
+    overhang_noise: Perlin,
+    cave_noise1: Perlin,
+    cave_noise2: Perlin,
+    ravine_noise: Perlin,
+    stone: i32,
+    water: i32,
+    lava: i32,
+}
+
+impl<'a> TerrainGenerator<'a> {
+    pub fn new(
+        seed: u64,
+        terrain_seeds: [u32; 3],
+        biome_sampler: biomes::BiomeSampler,
+        height: usize,
+        block_registry: &'a blocks::BlockRegistry,
+    ) -> TerrainGenerator<'a> {
+        return TerrainGenerator {
+            seed,
+            biome_sampler,
+            block_registry,
+            height,
+            noise1: Perlin::new(terrain_seeds[0]),
+            noise2: Perlin::new(terrain_seeds[1]),
+            noise3: Perlin::new(terrain_seeds[2]),
+            overhang_noise: Perlin::new(gamemap::derive_seed(seed, 5) as u32),
+            cave_noise1: Perlin::new(gamemap::derive_seed(seed, 6) as u32),
+            cave_noise2: Perlin::new(gamemap::derive_seed(seed, 7) as u32),
+            ravine_noise: Perlin::new(gamemap::derive_seed(seed, 8) as u32),
+            stone: block_registry.id("stone").unwrap(),
+            water: block_registry.id("water").unwrap(),
+            lava: block_registry.id("lava").unwrap(),
+        };
+    }
+
+    /// Returns the blocks of the column at world coordinates (x, z), from bottom to top.
+    pub fn column(&self, block_x: i32, block_z: i32) -> Vec<i32> {
+        let mut column = vec![blocks::AIR; self.height];
+        let world_x = block_x as f64 / TERRAIN_SCALE;
+        let world_z = block_z as f64 / TERRAIN_SCALE;
+        let (base_height, height_variation) = self.biome_sampler.terrain_shape_at(block_x, block_z);
+        let noise_1 = self.noise1.get([world_x, world_z]);
+        let noise_2 = 0.5 * self.noise2.get([world_x * 3.0, world_z * 3.0]);
+        let noise_3 = 0.25 * self.noise3.get([world_x * 7.0, world_z * 7.0]);
+        let noise_height = base_height + height_variation * (noise_1 + noise_2 + noise_3);
+
+        // A block is solid if it is below the terrain height, disturbed by the overhang noise.
+        // The noise is only sampled where it can make a difference, so that tall worlds are fast.
+        for y in 0..self.height {
+            if (y as f64) < noise_height - OVERHANG_STRENGTH {
+                column[y] = self.stone;
+                continue;
+            }
+            if (y as f64) >= noise_height + OVERHANG_STRENGTH {
+                break;
+            }
+            let overhang = OVERHANG_STRENGTH
+                * self.overhang_noise.get([
+                    block_x as f64 / OVERHANG_SCALE,
+                    y as f64 / OVERHANG_SCALE,
+                    block_z as f64 / OVERHANG_SCALE,
+                ]);
+            if y == 0 || (y as f64) < noise_height + overhang {
+                column[y] = self.stone;
+            }
+        }
+
+        // Cover every solid part exposed to the sky or an overhang with the biome's surface blocks.
+        let biome = self.biome_sampler.biome_at(block_x, block_z).properties();
+        let surface_block = self.block_registry.id(biome.surface_block).unwrap();
+        let subsurface_block = self.block_registry.id(biome.subsurface_block).unwrap();
+        let mut depth = 0; // Number of solid blocks above, since the last air block.
+        for y in (0..self.height).rev() {
+            if column[y] == blocks::AIR {
+                depth = 0;
+                continue;
+            }
+            if depth == 0 {
+                column[y] = surface_block;
+            } else if depth <= biome.subsurface_depth {
+                column[y] = subsurface_block;
+            }
+            depth += 1;
+        }
+
+        // Carve caves and ravines. The bottom layer is kept as the floor of the world.
+        let in_ravine = self
+            .ravine_noise
+            .get([block_x as f64 / RAVINE_SCALE, block_z as f64 / RAVINE_SCALE])
+            .abs()
+            < RAVINE_THRESHOLD;
+        for y in 1..self.height {
+            if column[y] == blocks::AIR {
+                continue;
+            }
+            let point = [
+                block_x as f64 / CAVE_SCALE,
+                y as f64 / CAVE_SCALE,
+                block_z as f64 / CAVE_SCALE,
+            ];
+            let in_cave = self.cave_noise1.get(point).abs() < CAVE_THRESHOLD
+                && self.cave_noise2.get(point).abs() < CAVE_THRESHOLD;
+            if in_cave || (in_ravine && y > 1) {
+                column[y] = blocks::AIR;
+            }
+        }
+
+        // Fill low caves with lava, and low places open to the sky with water.
+        let top = (0..self.height)
+            .rev()
+            .find(|&y| column[y] != blocks::AIR)
+            .unwrap_or(0);
+        for y in 1..=LAVA_LEVEL.min(top) {
+            if column[y] == blocks::AIR {
+                column[y] = self.lava;
+            }
+        }
+        for y in top + 1..=SEA_LEVEL.min(self.height - 1) {
+            column[y] = self.water;
+        }
+        return column;
+    }
+}
+
+/// The normal terrain, with biomes, caves, ores, trees and plants.
+pub struct NoiseGenerator<'a> {
+    terrain: TerrainGenerator<'a>,
+}
+
+impl<'a> WorldGenerator for NoiseGenerator<'a> {
+    /**
+    The terrain comes from `TerrainGenerator`. Then ore veins are placed into the remaining stone
+    (see the `ores` module), and trees and plants are placed on the surface (see the `decoration` module).
+    */
+    fn generate_blocks(&self, chunk_key: (i32, i32)) -> ChunkBlocks {
+        let mut blocks = ChunkBlocks::new(self.terrain.height);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let column = self.terrain.column(
+                    chunk_key.0 * CHUNK_SIZE as i32 + x as i32,
+                    chunk_key.1 * CHUNK_SIZE as i32 + z as i32,
+                );
+                for (y, &block_id) in column.iter().enumerate() {
+                    blocks.set(x, y, z, block_id);
+                }
+            }
+        }
+        ores::place_ores(
+            &mut blocks,
+            chunk_key,
+            self.terrain.seed,
+            &ores::default_ore_veins(self.terrain.height),
+            self.terrain.block_registry,
+        );
+        decoration::decorate(&mut blocks, chunk_key, &self.terrain);
+        return blocks;
+    }
+}
+
+/// Flat layers of blocks, the same in every chunk. Layers above the world are cut off.
+pub struct SuperflatGenerator {
+    /// Block IDs of the layers, from bottom to top.
+    layers: Vec<i32>,
+    height: usize,
+}
+
+impl SuperflatGenerator {
+    /**
+    Parse a layer preset: block string IDs separated by commas, from bottom to top.
+    A block may be prefixed with `<count>*` to repeat it, e.g. `bedrock,2*dirt,grass_block`.
+    Returns the block IDs of the layers, or a message describing what is wrong.
+    Unknown blocks are wrong, unless `unknown_as_air`: then their layers are air.
+    */
+    pub fn parse_layers(
+        preset: &str,
+        block_registry: &blocks::BlockRegistry,
+        unknown_as_air: bool,
+    ) -> Result<Vec<i32>, String> {
+        let mut layers = Vec::new();
+        for layer in preset.split(',') {
+            let layer = layer.trim();
+            let (count, block_name) = match layer.split_once('*') {
+                Some((count, block_name)) => match count.trim().parse::<usize>() {
+                    Ok(count) => (count, block_name.trim()),
+                    Err(_) => return Err(format!("Invalid layer count in \"{}\"", layer)),
+                },
+                None => (1, layer),
+            };
+            let block_id = match block_name {
+                "air" => blocks::AIR,
+                _ => match block_registry.id(block_name) {
+                    Some(block_id) => block_id,
+                    None if unknown_as_air => blocks::AIR,
+                    None => return Err(format!("Unknown block \"{}\" in layers", block_name)),
+                },
+            };
+            if layers.len() + count > gamemap::MAX_WORLD_HEIGHT {
+                return Err(format!("More than {} layers", gamemap::MAX_WORLD_HEIGHT));
+            }
+            layers.extend(std::iter::repeat(block_id).take(count));
+        }
+        return Ok(layers);
+    }
+}
+
+impl WorldGenerator for SuperflatGenerator {
+    fn generate_blocks(&self, _chunk_key: (i32, i32)) -> ChunkBlocks {
+        let mut blocks = ChunkBlocks::new(self.height);
+        for x in 0..CHUNK_SIZE {
+            for (y, &block_id) in self.layers.iter().enumerate() {
+                for z in 0..CHUNK_SIZE {
+                    blocks.set(x, y, z, block_id);
+                }
+            }
+        }
+        return blocks;
+    }
+}
+
+/// Size (in blocks) of the platform at the origin of a void world.
+const VOID_PLATFORM_SIZE: usize = 3;
+
+/// Nothing but a small platform at the origin, at half the world height.
+pub struct VoidGenerator {
+    platform: i32,
+    height: usize,
+}
+
+impl WorldGenerator for VoidGenerator {
+    fn generate_blocks(&self, chunk_key: (i32, i32)) -> ChunkBlocks {
+        let mut blocks = ChunkBlocks::new(self.height);
+        if chunk_key == (0, 0) {
+            for x in 0..VOID_PLATFORM_SIZE {
+                for z in 0..VOID_PLATFORM_SIZE {
+                    blocks.set(x, self.height / 2, z, self.platform);
+                }
+            }
+        }
+        return blocks;
+    }
+}